@@ -7,11 +7,32 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also generates the keymap's layers from `keymap.json` and the table of
+//! key positions from the KLE layout in `layout.json`, failing the build with
+//! what's wrong if either has a mistake in it. The layout is also compressed
+//! into the keyboard definition Vial reads from the keyboard.
+
+#[path = "build/json.rs"]
+mod json;
+#[path = "build/keymap.rs"]
+mod keymap;
+#[path = "build/kle.rs"]
+mod kle;
+#[path = "build/lzma.rs"]
+mod lzma;
+#[path = "build/vial.rs"]
+mod vial;
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The size of the matrix `BasicKeymap` is declared with
+const ROWS: usize = 5;
+const COLS: usize = 15;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -23,9 +44,32 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    generate(out, "keymap.json", "keymap.rs", keymap::generate);
+    generate(out, "layout.json", "layout.rs", kle::generate);
+    generate(out, "layout.json", "vial.rs", vial::generate);
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
+    // and the keymap here, we ensure the build script is only
+    // re-run when one of them is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=keymap.json");
+    println!("cargo:rerun-if-changed=layout.json");
+    println!("cargo:rerun-if-changed=build");
+}
+
+/// Writes the Rust generated from a file into the output directory, or stops
+/// the build with what's wrong with the file
+fn generate(out: &Path, input: &str, output: &str, generator: fn(&str) -> Result<String, String>) {
+    let generated = fs::read_to_string(input)
+        .map_err(|error| error.to_string())
+        .and_then(|text| generator(&text));
+
+    match generated {
+        Ok(generated) => fs::write(out.join(output), generated).unwrap(),
+        Err(error) => {
+            eprintln!("error: {input}: {error}");
+            process::exit(1);
+        }
+    }
 }
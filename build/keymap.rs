@@ -0,0 +1,442 @@
+//! Turns `keymap.json` into the table of layers `declare_keymaps!` reads a keymap's layers from.
+//!
+//! Each layer is a list of rows, and each row a list of actions written the way they are in Rust
+//! without the `Action::` and other prefixes, like `ModTap(LEFT_CTRL, Escape)`. A bare keycode
+//! is a `Key`, `_` is `Transparent`, and anything named in `aliases` is replaced by its action.
+//!
+//! Indices into the macros and other tables are checked against them as the keymap is compiled,
+//! as the tables are declared along with it in Rust.
+
+use crate::json::{self, Value};
+use crate::{COLS, ROWS};
+
+/// Matching `MAX_LAYERS` in the keymap
+const MAX_LAYERS: usize = 32;
+/// How many aliases can refer to each other before one is assumed to refer back to itself
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// The `Keyboard` usages from `usbd-human-interface-device`, besides the error codes
+#[rustfmt::skip]
+const KEYCODES: &[&str] = &[
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R",
+    "S", "T", "U", "V", "W", "X", "Y", "Z", "Keyboard1", "Keyboard2", "Keyboard3", "Keyboard4",
+    "Keyboard5", "Keyboard6", "Keyboard7", "Keyboard8", "Keyboard9", "Keyboard0", "ReturnEnter",
+    "Escape", "DeleteBackspace", "Tab", "Space", "Minus", "Equal", "LeftBrace", "RightBrace",
+    "Backslash", "NonUSHash", "Semicolon", "Apostrophe", "Grave", "Comma", "Dot", "ForwardSlash",
+    "CapsLock", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "PrintScreen", "ScrollLock", "Pause", "Insert", "Home", "PageUp", "DeleteForward", "End",
+    "PageDown", "RightArrow", "LeftArrow", "DownArrow", "UpArrow", "KeypadNumLockAndClear",
+    "KeypadDivide", "KeypadMultiply", "KeypadSubtract", "KeypadAdd", "KeypadEnter", "Keypad1",
+    "Keypad2", "Keypad3", "Keypad4", "Keypad5", "Keypad6", "Keypad7", "Keypad8", "Keypad9",
+    "Keypad0", "KeypadDot", "NonUSBackslash", "Application", "Power", "KeypadEqual", "F13", "F14",
+    "F15", "F16", "F17", "F18", "F19", "F20", "F21", "F22", "F23", "F24", "Execute", "Help",
+    "Menu", "Select", "Stop", "Again", "Undo", "Cut", "Copy", "Paste", "Find", "Mute", "VolumeUp",
+    "VolumeDown", "LockingCapsLock", "LockingNumLock", "LockingScrollLock", "KeypadComma",
+    "KeypadEqualSign", "Kanji1", "Kanji2", "Kanji3", "Kanji4", "Kanji5", "Kanji6", "Kanji7",
+    "Kanji8", "Kanji9", "LANG1", "LANG2", "LANG3", "LANG4", "LANG5", "LANG6", "LANG7", "LANG8",
+    "LANG9", "AlternateErase", "SysReqAttention", "Cancel", "Clear", "Prior", "Return",
+    "Separator", "Out", "Oper", "ClearAgain", "CrSelProps", "ExSel", "LeftControl", "LeftShift",
+    "LeftAlt", "LeftGUI", "RightControl", "RightShift", "RightAlt", "RightGUI",
+];
+
+const MODIFIERS: &[&str] = &[
+    "LEFT_CTRL",
+    "LEFT_SHIFT",
+    "LEFT_ALT",
+    "LEFT_GUI",
+    "RIGHT_CTRL",
+    "RIGHT_SHIFT",
+    "RIGHT_ALT",
+    "RIGHT_GUI",
+];
+
+const CONSUMER_USAGES: &[&str] = &[
+    "PLAY_PAUSE",
+    "NEXT_TRACK",
+    "PREVIOUS_TRACK",
+    "MUTE",
+    "VOLUME_UP",
+    "VOLUME_DOWN",
+    "BRIGHTNESS_UP",
+    "BRIGHTNESS_DOWN",
+    "CALCULATOR",
+    "BROWSER",
+];
+
+const SYSTEM_USAGES: &[&str] = &["PowerDown", "Sleep", "WakeUp"];
+const MOUSE_DIRECTIONS: &[&str] = &["Up", "Down", "Left", "Right"];
+const MOUSE_BUTTONS: &[&str] = &["Left", "Right", "Middle", "Back", "Forward"];
+const UNICODE_MODES: &[&str] = &["Linux", "MacOs", "WinCompose"];
+
+/// Actions that don't take anything
+const UNIT_ACTIONS: &[&str] = &[
+    "NoOp",
+    "Transparent",
+    "LayerLock",
+    "StopRecording",
+    "Leader",
+    "ToggleAutoShift",
+    "CapsWord",
+    "GraveEscape",
+    "ToggleNkro",
+];
+
+/// The Rust source for the layer table described by a keymap file
+pub fn generate(text: &str) -> Result<String, String> {
+    let root = json::parse(text)?;
+
+    let Value::Object(sections) = &root else {
+        return Err(format!(
+            "the keymap should be an object, not {}",
+            root.kind()
+        ));
+    };
+    if let Some((name, _)) = sections
+        .iter()
+        .find(|(name, _)| !matches!(name.as_str(), "aliases" | "layers"))
+    {
+        return Err(format!(
+            "unknown section `{name}`, expected `aliases` or `layers`"
+        ));
+    }
+
+    let aliases = match root.get("aliases") {
+        None => &[][..],
+        Some(Value::Object(aliases)) => aliases,
+        Some(other) => {
+            return Err(format!(
+                "`aliases` should be an object, not {}",
+                other.kind()
+            ));
+        }
+    };
+    let layers = match root.get("layers") {
+        Some(Value::Array(layers)) => layers,
+        Some(other) => {
+            return Err(format!("`layers` should be an array, not {}", other.kind()));
+        }
+        None => return Err("there is no `layers` section".into()),
+    };
+    if layers.is_empty() || layers.len() > MAX_LAYERS {
+        return Err(format!(
+            "there are {} layers, but there need to be between 1 and {MAX_LAYERS}",
+            layers.len()
+        ));
+    }
+
+    let keymap = Keymap {
+        aliases,
+        layers: layers.len(),
+    };
+
+    let mut output = format!(
+        "// Generated by build.rs from keymap.json\n\n\
+         pub const KEYMAP_LAYERS: [[[Action; {COLS}]; {ROWS}]; {}] = [\n",
+        layers.len()
+    );
+    let mut checks = Vec::new();
+    for (index, layer) in layers.iter().enumerate() {
+        output += &keymap.layer(index, layer, &mut checks)?;
+    }
+    output += "];\n";
+
+    if !checks.is_empty() {
+        output += "\n// Fails to compile if a key uses something past the end of its table\n";
+        // Index 0 comes out as a comparison of a length with zero
+        output += "#[allow(clippy::len_zero)]\nconst _: () = {\n";
+        for check in checks {
+            output += &format!("    {check}\n");
+        }
+        output += "};\n";
+    }
+
+    Ok(output)
+}
+
+struct Keymap<'a> {
+    aliases: &'a [(String, Value)],
+    layers: usize,
+}
+
+impl Keymap<'_> {
+    fn layer(
+        &self,
+        index: usize,
+        layer: &Value,
+        checks: &mut Vec<String>,
+    ) -> Result<String, String> {
+        let name = match layer.get("name") {
+            Some(Value::String(name)) => Some(name.as_str()),
+            _ => None,
+        };
+        let context = match name {
+            Some(name) => format!("layer {index} (\"{name}\")"),
+            None => format!("layer {index}"),
+        };
+
+        let rows = match layer.get("keys") {
+            Some(Value::Array(rows)) => rows,
+            Some(other) => {
+                return Err(format!(
+                    "{context}: `keys` should be an array of rows, not {}",
+                    other.kind()
+                ));
+            }
+            None => return Err(format!("{context} has no `keys`")),
+        };
+        if rows.len() > ROWS {
+            return Err(format!(
+                "{context} has {} rows, but the matrix only has {ROWS}",
+                rows.len()
+            ));
+        }
+
+        let mut output = format!("    // {}\n    [\n", name.unwrap_or("Layer"));
+        for row in 0..ROWS {
+            let keys = match rows.get(row) {
+                None => &[][..],
+                Some(Value::Array(keys)) => keys,
+                Some(other) => {
+                    return Err(format!(
+                        "{context}, row {row} should be an array of keys, not {}",
+                        other.kind()
+                    ));
+                }
+            };
+            if keys.len() > COLS {
+                return Err(format!(
+                    "{context}, row {row} has {} keys, but the matrix only has {COLS} columns",
+                    keys.len()
+                ));
+            }
+
+            // Keys left off the end of a row fall through to the layers below
+            let actions = (0..COLS)
+                .map(|col| match keys.get(col) {
+                    None => Ok("Action::Transparent".into()),
+                    Some(Value::String(action)) => {
+                        let at = format!("{context}, row {row}, column {col}");
+                        let action = self
+                            .action(action, 0)
+                            .map_err(|error| format!("{at}: {error}"))?;
+
+                        checks.extend(index_check(&action, &at));
+                        Ok(action)
+                    }
+                    Some(other) => Err(format!(
+                        "{context}, row {row}, column {col} should be a string, not {}",
+                        other.kind()
+                    )),
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+
+            output += &format!("        [{}],\n", actions.join(", "));
+        }
+        output += "    ],\n";
+
+        Ok(output)
+    }
+
+    fn action(&self, text: &str, depth: usize) -> Result<String, String> {
+        let text = text.trim();
+
+        if let Some((_, alias)) = self.aliases.iter().find(|(name, _)| name == text) {
+            let Value::String(alias) = alias else {
+                return Err(format!(
+                    "alias `{text}` should be a string, not {}",
+                    alias.kind()
+                ));
+            };
+            if depth >= MAX_ALIAS_DEPTH {
+                return Err(format!("alias `{text}` looks to refer back to itself"));
+            }
+
+            return self
+                .action(alias, depth + 1)
+                .map_err(|error| format!("in alias `{text}`: {error}"));
+        }
+
+        if text == "_" {
+            return Ok("Action::Transparent".into());
+        }
+
+        let Some((name, rest)) = text.split_once('(') else {
+            return if UNIT_ACTIONS.contains(&text) {
+                Ok(format!("Action::{text}"))
+            } else {
+                Ok(format!("Action::Key({})", keycode(text)?))
+            };
+        };
+        let Some(arguments) = rest.strip_suffix(')') else {
+            return Err(format!("`{text}` is missing its closing `)`"));
+        };
+        let name = name.trim();
+
+        // Split apart below, which would break up characters like `,`
+        if name == "Unicode" {
+            let mut chars = arguments.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(character), None) => Ok(format!("Action::Unicode({character:?})")),
+                _ => Err(format!(
+                    "`Unicode` takes a single character, not `{arguments}`"
+                )),
+            };
+        }
+
+        let arguments: Vec<&str> = arguments.split(',').map(str::trim).collect();
+        let action = match name {
+            "Key" => {
+                let [key] = count(name, &arguments)?;
+                format!("Key({})", keycode(key)?)
+            }
+            "Modifiers" | "OneShotModifiers" => {
+                let [held] = count(name, &arguments)?;
+                format!("{name}({})", modifiers(held)?)
+            }
+            "ModifiedKey" | "ModTap" | "SpaceCadet" => {
+                let [held, key] = count(name, &arguments)?;
+                format!("{name}({}, {})", modifiers(held)?, keycode(key)?)
+            }
+            "LayerTap" => {
+                let [layer, key] = count(name, &arguments)?;
+                format!("LayerTap({}, {})", self.layer_index(layer)?, keycode(key)?)
+            }
+            "MomentaryLayer" | "ToggleLayer" | "DefaultLayer" | "OneShotLayer" => {
+                let [layer] = count(name, &arguments)?;
+                format!("{name}({})", self.layer_index(layer)?)
+            }
+            "TapDance" | "Macro" | "RecordMacro" | "PlayRecording" | "ViaMacro" => {
+                let [index] = count(name, &arguments)?;
+                let index: u8 = index
+                    .parse()
+                    .map_err(|_| format!("`{index}` isn't an index from 0 to 255"))?;
+                format!("{name}({index})")
+            }
+            "MouseMove" | "MouseWheel" => {
+                let [direction] = count(name, &arguments)?;
+                format!(
+                    "{name}({})",
+                    one_of(direction, MOUSE_DIRECTIONS, "MouseDirection")?
+                )
+            }
+            "MouseButton" => {
+                let [button] = count(name, &arguments)?;
+                format!(
+                    "MouseButton({})",
+                    one_of(button, MOUSE_BUTTONS, "MouseButton")?
+                )
+            }
+            "Consumer" => {
+                let [usage] = count(name, &arguments)?;
+                format!(
+                    "Consumer({})",
+                    one_of(usage, CONSUMER_USAGES, "ConsumerUsage")?
+                )
+            }
+            "System" => {
+                let [usage] = count(name, &arguments)?;
+                format!("System({})", one_of(usage, SYSTEM_USAGES, "SystemUsage")?)
+            }
+            "SetUnicodeMode" => {
+                let [mode] = count(name, &arguments)?;
+                format!(
+                    "SetUnicodeMode({})",
+                    one_of(mode, UNICODE_MODES, "UnicodeMode")?
+                )
+            }
+            _ if UNIT_ACTIONS.contains(&name) => {
+                return Err(format!("`{name}` doesn't take anything in brackets"));
+            }
+            _ => return Err(format!("unknown action `{name}`")),
+        };
+
+        Ok(format!("Action::{action}"))
+    }
+
+    fn layer_index(&self, layer: &str) -> Result<usize, String> {
+        match layer.parse() {
+            Ok(index) if index < self.layers => Ok(index),
+            Ok(index) => Err(format!(
+                "there is no layer {index}, the keymap has {} layers",
+                self.layers
+            )),
+            Err(_) => Err(format!("`{layer}` isn't a layer number")),
+        }
+    }
+}
+
+/// An assertion that an action's index is in the table it indexes into, if it has one
+fn index_check(action: &str, at: &str) -> Option<String> {
+    let (name, index) = action.strip_prefix("Action::")?.split_once('(')?;
+    let index: u8 = index.strip_suffix(')')?.parse().ok()?;
+
+    let keymap = format!("<BasicKeymap as KeyMap<{ROWS}, {COLS}>>");
+    let (what, length) = match name {
+        "TapDance" => ("tap dance", format!("{keymap}::TAP_DANCES.len()")),
+        "Macro" => ("macro", format!("{keymap}::MACROS.len()")),
+        "RecordMacro" | "PlayRecording" => (
+            "recording slot",
+            "crate::constants::DYNAMIC_MACRO_SLOTS".into(),
+        ),
+        "ViaMacro" => (
+            "VIA macro",
+            "crate::constants::VIA_MACRO_COUNT as usize".into(),
+        ),
+        _ => return None,
+    };
+
+    let message = format!("keymap.json: {at}: there is no {what} {index}");
+    Some(format!("assert!({index} < {length}, {message:?});"))
+}
+
+/// The arguments given to an action, if there are as many as it takes
+fn count<'a, const N: usize>(name: &str, arguments: &[&'a str]) -> Result<[&'a str; N], String> {
+    arguments.try_into().map_err(|_| {
+        format!(
+            "`{name}` takes {N} arguments, but was given {}",
+            arguments.len()
+        )
+    })
+}
+
+fn keycode(name: &str) -> Result<String, String> {
+    if KEYCODES.contains(&name) {
+        return Ok(format!("Keyboard::{name}"));
+    }
+
+    match KEYCODES
+        .iter()
+        .find(|keycode| keycode.eq_ignore_ascii_case(name))
+    {
+        Some(keycode) => Err(format!(
+            "unknown keycode `{name}`, did you mean `{keycode}`?"
+        )),
+        None => Err(format!("unknown keycode `{name}`")),
+    }
+}
+
+/// Modifiers joined with `|`, like `LEFT_CTRL | LEFT_SHIFT`
+fn modifiers(names: &str) -> Result<String, String> {
+    let mut output = String::new();
+
+    for name in names.split('|').map(str::trim) {
+        let modifier = one_of(name, MODIFIERS, "Modifiers")?;
+        if output.is_empty() {
+            output = modifier;
+        } else {
+            output = format!("{output}.union({modifier})");
+        }
+    }
+    Ok(output)
+}
+
+fn one_of(name: &str, names: &[&str], type_name: &str) -> Result<String, String> {
+    if names.contains(&name) {
+        Ok(format!("{type_name}::{name}"))
+    } else {
+        Err(format!(
+            "unknown {type_name} `{name}`, expected one of {}",
+            names.join(", ")
+        ))
+    }
+}
@@ -0,0 +1,165 @@
+//! Turns the board's layout from keyboard-layout-editor.com (KLE) into a table of where each key
+//! physically sits.
+//!
+//! The layout is the raw data KLE downloads, with each key's top left legend giving its place in
+//! the matrix as `row,col`, the same way VIA reads layouts.
+
+use crate::json::{self, Value};
+use crate::{COLS, ROWS};
+
+/// Positions are stored as whole hundredths of a key unit, matching `KEY_UNIT`
+const KEY_UNIT: f64 = 100.0;
+
+struct Key {
+    row: usize,
+    col: usize,
+    x: f64,
+    y: f64,
+    width: f64,
+}
+
+/// The Rust source for the table of key positions described by a KLE layout
+pub fn generate(text: &str) -> Result<String, String> {
+    let root = json::parse(text)?;
+    let Value::Array(rows) = &root else {
+        return Err(format!(
+            "the layout should be an array of rows, not {}",
+            root.kind()
+        ));
+    };
+
+    // The board's name and other details can come before the rows
+    let rows = match rows.split_first() {
+        Some((Value::Object(_), rows)) => rows,
+        _ => rows,
+    };
+
+    let mut keys: Vec<Key> = Vec::new();
+    let mut y = 0.0;
+
+    for (index, row) in rows.iter().enumerate() {
+        let items = match row {
+            Value::Array(items) => items,
+            other => {
+                return Err(format!(
+                    "row {index} should be an array, not {}",
+                    other.kind()
+                ));
+            }
+        };
+
+        let mut x = 0.0;
+        // These only last until the next key
+        let mut width = 1.0;
+        let mut decal = false;
+
+        for item in items {
+            match item {
+                Value::Object(properties) => {
+                    for (name, value) in properties {
+                        match (name.as_str(), value) {
+                            ("x", Value::Number(offset)) => x += offset,
+                            ("y", Value::Number(offset)) => y += offset,
+                            ("w", Value::Number(w)) => width = *w,
+                            ("d", Value::Bool(d)) => decal = *d,
+                            ("r" | "rx" | "ry", _) => {
+                                return Err(format!("row {index}: rotated keys aren't supported"));
+                            }
+                            // Colours, legends, heights and the like don't change where keys are
+                            _ => {}
+                        }
+                    }
+                }
+                Value::String(legends) => {
+                    // Decals are labels on the layout rather than keys
+                    if !decal {
+                        let (row, col) = matrix_position(legends)
+                            .map_err(|error| format!("row {index}, key {:?}: {error}", legends))?;
+
+                        if let Some(other) =
+                            keys.iter().find(|key| (key.row, key.col) == (row, col))
+                        {
+                            return Err(format!(
+                                "row {index}: two keys are at {row},{col} in the matrix, at x = {} \
+                                 and x = {x}",
+                                other.x
+                            ));
+                        }
+                        if x < 0.0 || y < 0.0 {
+                            return Err(format!(
+                                "row {index}: key {row},{col} is above or left of the board"
+                            ));
+                        }
+
+                        keys.push(Key {
+                            row,
+                            col,
+                            x,
+                            y,
+                            width,
+                        });
+                    }
+
+                    x += width;
+                    width = 1.0;
+                    decal = false;
+                }
+                other => {
+                    return Err(format!(
+                        "row {index} should only hold keys and their properties, not {}",
+                        other.kind()
+                    ));
+                }
+            }
+        }
+
+        y += 1.0;
+    }
+
+    let mut output = format!(
+        "// Generated by build.rs from layout.json\n\n\
+         pub const KEY_POSITIONS: [KeyPosition; {}] = [\n",
+        keys.len()
+    );
+    for key in &keys {
+        output += &format!(
+            "    KeyPosition {{ row: {}, col: {}, x: {}, y: {}, width: {} }},\n",
+            key.row,
+            key.col,
+            units(key.x),
+            units(key.y),
+            units(key.width)
+        );
+    }
+    output += "];\n";
+
+    Ok(output)
+}
+
+/// The matrix position in a key's top left legend, which KLE puts before the other legends
+fn matrix_position(legends: &str) -> Result<(usize, usize), String> {
+    let legend = legends.split('\n').next().unwrap_or_default();
+    let Some((row, col)) = legend.split_once(',') else {
+        return Err("expected the top left legend to be the key's `row,col` in the matrix".into());
+    };
+
+    let row: usize = row
+        .trim()
+        .parse()
+        .map_err(|_| format!("`{row}` isn't a row number"))?;
+    let col: usize = col
+        .trim()
+        .parse()
+        .map_err(|_| format!("`{col}` isn't a column number"))?;
+
+    if row >= ROWS || col >= COLS {
+        return Err(format!(
+            "{row},{col} is outside the matrix, which has {ROWS} rows and {COLS} columns"
+        ));
+    }
+    Ok((row, col))
+}
+
+fn units(value: f64) -> u16 {
+    (value * KEY_UNIT).round() as u16
+}
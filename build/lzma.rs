@@ -0,0 +1,350 @@
+//! A small LZMA compressor, writing the legacy `.lzma` format that Vial decompresses its keyboard
+//! definition from.
+//!
+//! Matches are found greedily and only ever coded as new distances, which is a lot simpler than a
+//! real encoder and still shrinks a definition several times over.
+
+use std::collections::HashMap;
+
+// Literal context bits, literal position bits and position bits, the format's usual choice
+const LC: usize = 3;
+const LP: usize = 0;
+const PB: usize = 2;
+
+const DICTIONARY_SIZE: u32 = 1 << 16;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 273;
+// How many earlier places with the same three bytes are tried for each match
+const MAX_CANDIDATES: usize = 64;
+
+const STATES: usize = 12;
+const POS_STATES: usize = 1 << PB;
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_INIT: u16 = 1 << (PROBABILITY_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const END_POS_MODEL_INDEX: u32 = 14;
+const FULL_DISTANCES: usize = 1 << (END_POS_MODEL_INDEX / 2);
+const ALIGN_BITS: u32 = 4;
+
+/// `data` compressed, with the header giving its properties and uncompressed size
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![((PB * 5 + LP) * 9 + LC) as u8];
+    output.extend_from_slice(&DICTIONARY_SIZE.to_le_bytes());
+    output.extend_from_slice(&(data.len() as u64).to_le_bytes());
+
+    let mut encoder = Encoder::new();
+    let mut candidates: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let (length, distance) = longest_match(data, position, &candidates);
+
+        if length >= MIN_MATCH {
+            encoder.encode_match(position, length, distance);
+        } else {
+            encoder.encode_literal(data, position);
+        }
+
+        let end = position + length.max(1);
+        while position < end {
+            if let Some(key) = data.get(position..position + MIN_MATCH) {
+                candidates.entry(key).or_default().push(position);
+            }
+            position += 1;
+        }
+    }
+
+    output.extend(encoder.range.finish());
+    output
+}
+
+/// The length and distance back of the longest earlier copy of the bytes at `position`
+fn longest_match(
+    data: &[u8],
+    position: usize,
+    candidates: &HashMap<&[u8], Vec<usize>>,
+) -> (usize, usize) {
+    let Some(earlier) = data
+        .get(position..position + MIN_MATCH)
+        .and_then(|key| candidates.get(key))
+    else {
+        return (0, 0);
+    };
+
+    let limit = (data.len() - position).min(MAX_MATCH);
+    let mut best = (0, 0);
+
+    for &start in earlier.iter().rev().take(MAX_CANDIDATES) {
+        let distance = position - start;
+        if distance > DICTIONARY_SIZE as usize {
+            break;
+        }
+
+        let length = (0..limit)
+            .take_while(|&i| data[start + i] == data[position + i])
+            .count();
+        if length > best.0 {
+            best = (length, distance);
+        }
+    }
+
+    best
+}
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    output: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new() -> Self {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            output: Vec::new(),
+        }
+    }
+
+    fn encode_bit(&mut self, probability: &mut u16, bit: u32) {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+
+        if bit == 0 {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> MOVE_BITS;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> MOVE_BITS;
+        }
+
+        self.normalize();
+    }
+
+    /// Bits with an even chance of being either, which aren't worth modelling
+    fn encode_direct(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            self.normalize();
+        }
+    }
+
+    fn normalize(&mut self) {
+        while self.range < 1 << 24 {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        // Bytes are held back until it's known whether a carry will ripple into them
+        if self.low < 0xFF00_0000 || self.low >= 1 << 32 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.output
+    }
+}
+
+fn encode_tree(range: &mut RangeEncoder, probabilities: &mut [u16], bits: u32, value: u32) {
+    let mut index = 1;
+    for i in (0..bits).rev() {
+        let bit = (value >> i) & 1;
+        range.encode_bit(&mut probabilities[index], bit);
+        index = (index << 1) | bit as usize;
+    }
+}
+
+fn encode_reverse_tree(range: &mut RangeEncoder, probabilities: &mut [u16], bits: u32, value: u32) {
+    let mut index = 1;
+    for i in 0..bits {
+        let bit = (value >> i) & 1;
+        range.encode_bit(&mut probabilities[index], bit);
+        index = (index << 1) | bit as usize;
+    }
+}
+
+struct LengthEncoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 1 << 3]; POS_STATES],
+    mid: [[u16; 1 << 3]; POS_STATES],
+    high: [u16; 1 << 8],
+}
+
+impl LengthEncoder {
+    fn new() -> Self {
+        LengthEncoder {
+            choice: PROBABILITY_INIT,
+            choice2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; 1 << 3]; POS_STATES],
+            mid: [[PROBABILITY_INIT; 1 << 3]; POS_STATES],
+            high: [PROBABILITY_INIT; 1 << 8],
+        }
+    }
+
+    fn encode(&mut self, range: &mut RangeEncoder, length: usize, pos_state: usize) {
+        let length = (length - 2) as u32;
+
+        if length < 8 {
+            range.encode_bit(&mut self.choice, 0);
+            encode_tree(range, &mut self.low[pos_state], 3, length);
+        } else if length < 16 {
+            range.encode_bit(&mut self.choice, 1);
+            range.encode_bit(&mut self.choice2, 0);
+            encode_tree(range, &mut self.mid[pos_state], 3, length - 8);
+        } else {
+            range.encode_bit(&mut self.choice, 1);
+            range.encode_bit(&mut self.choice2, 1);
+            encode_tree(range, &mut self.high, 8, length - 16);
+        }
+    }
+}
+
+struct Encoder {
+    range: RangeEncoder,
+    state: usize,
+    // The distance of the last match, less one, which literals straight after it are coded against
+    rep0: usize,
+    is_match: [[u16; POS_STATES]; STATES],
+    is_rep: [u16; STATES],
+    literals: Vec<[u16; 0x300]>,
+    lengths: LengthEncoder,
+    pos_slots: [[u16; 1 << 6]; 4],
+    // Indexed from one, as the trees are
+    pos_special: [u16; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << ALIGN_BITS],
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Encoder {
+            range: RangeEncoder::new(),
+            state: 0,
+            rep0: 0,
+            is_match: [[PROBABILITY_INIT; POS_STATES]; STATES],
+            is_rep: [PROBABILITY_INIT; STATES],
+            literals: vec![[PROBABILITY_INIT; 0x300]; 1 << (LC + LP)],
+            lengths: LengthEncoder::new(),
+            pos_slots: [[PROBABILITY_INIT; 1 << 6]; 4],
+            pos_special: [PROBABILITY_INIT; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROBABILITY_INIT; 1 << ALIGN_BITS],
+        }
+    }
+
+    fn encode_literal(&mut self, data: &[u8], position: usize) {
+        let pos_state = position & (POS_STATES - 1);
+        self.range
+            .encode_bit(&mut self.is_match[self.state][pos_state], 0);
+
+        let previous = if position > 0 { data[position - 1] } else { 0 };
+        let context = ((position & ((1 << LP) - 1)) << LC) + (previous as usize >> (8 - LC));
+        let probabilities = &mut self.literals[context];
+        let mut symbol = data[position] as u32 | 0x100;
+
+        if self.state < 7 {
+            while symbol < 0x10000 {
+                self.range.encode_bit(
+                    &mut probabilities[(symbol >> 8) as usize],
+                    (symbol >> 7) & 1,
+                );
+                symbol <<= 1;
+            }
+        } else {
+            // Straight after a match, the byte the match would have continued with is used as
+            // extra context for as long as it agrees
+            let mut match_byte = data[position - self.rep0 - 1] as u32;
+            let mut offset = 0x100;
+            while symbol < 0x10000 {
+                match_byte <<= 1;
+                let index = offset + (match_byte & offset) + (symbol >> 8);
+                self.range
+                    .encode_bit(&mut probabilities[index as usize], (symbol >> 7) & 1);
+                symbol <<= 1;
+                offset &= !(match_byte ^ symbol);
+            }
+        }
+
+        self.state = match self.state {
+            0..4 => 0,
+            4..10 => self.state - 3,
+            _ => self.state - 6,
+        };
+    }
+
+    fn encode_match(&mut self, position: usize, length: usize, distance: usize) {
+        let pos_state = position & (POS_STATES - 1);
+        self.range
+            .encode_bit(&mut self.is_match[self.state][pos_state], 1);
+        self.range.encode_bit(&mut self.is_rep[self.state], 0);
+        self.lengths.encode(&mut self.range, length, pos_state);
+
+        let distance = (distance - 1) as u32;
+        let pos_slot = if distance < 4 {
+            distance
+        } else {
+            let bits = u32::BITS - 1 - distance.leading_zeros();
+            (bits << 1) | ((distance >> (bits - 1)) & 1)
+        };
+        let length_state = (length - 2).min(3);
+        encode_tree(
+            &mut self.range,
+            &mut self.pos_slots[length_state],
+            6,
+            pos_slot,
+        );
+
+        if pos_slot >= 4 {
+            let footer_bits = (pos_slot >> 1) - 1;
+            let base = (2 | (pos_slot & 1)) << footer_bits;
+            let reduced = distance - base;
+
+            if pos_slot < END_POS_MODEL_INDEX {
+                let start = (base - pos_slot) as usize;
+                encode_reverse_tree(
+                    &mut self.range,
+                    &mut self.pos_special[start..],
+                    footer_bits,
+                    reduced,
+                );
+            } else {
+                self.range
+                    .encode_direct(reduced >> ALIGN_BITS, footer_bits - ALIGN_BITS);
+                encode_reverse_tree(
+                    &mut self.range,
+                    &mut self.align,
+                    ALIGN_BITS,
+                    reduced & ((1 << ALIGN_BITS) - 1),
+                );
+            }
+        }
+
+        self.rep0 = distance as usize;
+        self.state = if self.state < 7 { 7 } else { 10 };
+    }
+}
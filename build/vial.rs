@@ -0,0 +1,47 @@
+//! Builds the keyboard definition Vial reads from the keyboard itself, so there's no separate JSON
+//! file to load. It is VIA's definition format, with the KLE layout from `layout.json` as the
+//! keymap, compressed with LZMA as Vial expects.
+
+use crate::json::{self, Value};
+use crate::lzma;
+use crate::{COLS, ROWS};
+
+// The same as the USB descriptors in main.rs
+const NAME: &str = "The Daudboard";
+const VENDOR_ID: u16 = 0x1209;
+const PRODUCT_ID: u16 = 0x0001;
+
+/// The Rust source for the compressed definition, given the KLE layout
+pub fn generate(layout: &str) -> Result<String, String> {
+    // The layout is checked over properly for the table of key positions, this only makes sure
+    // it's something Vial could read
+    let root = json::parse(layout)?;
+    if !matches!(root, Value::Array(_)) {
+        return Err(format!(
+            "the layout should be an array of rows, not {}",
+            root.kind()
+        ));
+    }
+
+    let definition = format!(
+        "{{\"name\":\"{NAME}\",\"vendorId\":\"0x{VENDOR_ID:04X}\",\"productId\":\"0x{PRODUCT_ID:04X}\",\
+         \"matrix\":{{\"rows\":{ROWS},\"cols\":{COLS}}},\"layouts\":{{\"keymap\":{}}}}}",
+        layout.trim()
+    );
+    let compressed = lzma::compress(definition.as_bytes());
+
+    let mut output = format!(
+        "// Generated by build.rs from layout.json\n\n\
+         pub const VIAL_DEFINITION: [u8; {}] = [",
+        compressed.len()
+    );
+    for (i, byte) in compressed.iter().enumerate() {
+        if i % 16 == 0 {
+            output += "\n   ";
+        }
+        output += &format!(" 0x{byte:02X},");
+    }
+    output += "\n];\n";
+
+    Ok(output)
+}
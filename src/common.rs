@@ -53,3 +53,60 @@ impl<'a> ClampedTimer<'a> {
         self.is_clamped
     }
 }
+
+/// A fixed capacity FIFO queue
+pub struct Queue<T: Copy, const N: usize> {
+    buffer: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            buffer: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Hands the item back if there is no space for it
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.len >= N {
+            return Err(item);
+        }
+
+        self.buffer[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let item = self.buffer[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+
+        item
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Overwrites the newest item when full, since losing an intermediate state is better than
+    /// losing the latest one
+    pub fn push_latest(&mut self, item: T) {
+        if let Err(item) = self.push(item) {
+            self.buffer[(self.head + self.len - 1) % N] = Some(item);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).filter_map(|i| self.buffer[(self.head + i) % N].as_ref())
+    }
+}
@@ -1,11 +1,60 @@
+use crate::debounce::{DebounceAlgorithm, DebounceTiming};
 use rp2040_hal::fugit::{HertzU32, MicrosDurationU32};
 
 pub const NUMBER_OF_LEDS: usize = 68;
 pub const RESET_DELAY: MicrosDurationU32 = MicrosDurationU32::micros((60 * NUMBER_OF_LEDS) as u32);
 pub const EFFECT_RATE: HertzU32 = HertzU32::nanos(500);
+// The LED under CapsLock, counting along the rows the way the strip snakes through them
+pub const CAPS_LOCK_LED: usize = 30;
 
 //
 pub const USB_ENDPOINT_POLL_RATE: HertzU32 = HertzU32::Hz(1000);
 pub const KEYBOARD_POLLING_RATE: HertzU32 = HertzU32::Hz(4000);
 pub const ROWS_PER_POLL: u32 = 4;
 pub const HID_TICK_RATE: HertzU32 = HertzU32::millis(1);
+pub const KEY_EVENT_QUEUE_SIZE: usize = 32;
+pub const DEBOUNCE_TIME: MicrosDurationU32 = MicrosDurationU32::millis(5);
+pub const DEBOUNCE_ALGORITHM: DebounceAlgorithm = DebounceAlgorithm::EagerPerKey;
+// Keys that need a different time to settle than DEBOUNCE_TIME, as (row, col, timing)
+pub const DEBOUNCE_KEY_TIMINGS: &[(usize, usize, DebounceTiming)] = &[];
+
+// Keymap
+pub const TAPPING_TERM: MicrosDurationU32 = MicrosDurationU32::millis(200);
+pub const REPORT_QUEUE_SIZE: usize = 16;
+pub const TAP_HOLD_BUFFER_SIZE: usize = 16;
+pub const COMBO_TERM: MicrosDurationU32 = MicrosDurationU32::millis(50);
+pub const COMBO_BUFFER_SIZE: usize = 8;
+pub const MAX_COMBOS: usize = 32;
+pub const MAX_TAP_DANCES: usize = 16;
+pub const MAX_TAP_DANCE_TAPS: usize = 4;
+pub const MAX_KEY_OVERRIDES: usize = 16;
+pub const MACRO_QUEUE_SIZE: usize = 4;
+pub const DYNAMIC_MACRO_SLOTS: usize = 2;
+pub const DYNAMIC_MACRO_LENGTH: usize = 128;
+pub const LEADER_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::millis(500);
+pub const LEADER_MAX_LENGTH: usize = 4;
+pub const AUTO_SHIFT_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::millis(175);
+pub const ONE_SHOT_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::secs(3);
+pub const CAPS_WORD_IDLE_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::secs(5);
+pub const MOUSE_MOVE_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(16);
+pub const MOUSE_WHEEL_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(80);
+pub const DYNAMIC_KEYMAP_LAYERS: usize = 4;
+
+// VIA
+pub const VIA_MACRO_COUNT: u8 = 16;
+pub const VIA_MACRO_BUFFER_SIZE: usize = 512;
+
+// Vial
+// Random, so Vial can tell this board apart from others with the same definition
+pub const VIAL_KEYBOARD_UID: [u8; 8] = [0x5D, 0xA7, 0x1E, 0x0B, 0x93, 0xC4, 0x26, 0xF8];
+// Esc and Enter, held together to let Vial change security sensitive settings
+pub const VIAL_UNLOCK_KEYS: [(u8, u8); 2] = [(0, 14), (2, 13)];
+pub const VIAL_UNLOCK_HOLD_TIME: MicrosDurationU32 = MicrosDurationU32::secs(5);
+
+// Storage
+// The end of the flash, which memory.x keeps the program out of
+pub const STORAGE_SIZE: usize = 64 * 1024;
+pub const STORAGE_OFFSET: usize = 2048 * 1024 - STORAGE_SIZE;
+// How long VIA has to stop changing things before they're saved, so a whole keymap being loaded
+// doesn't fill the flash with every step of it
+pub const SETTINGS_SAVE_DELAY: MicrosDurationU32 = MicrosDurationU32::secs(1);
@@ -0,0 +1,375 @@
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+/// How long a key has to settle before a change in either direction is trusted
+#[derive(Copy, Clone)]
+pub struct DebounceTiming {
+    pub press: MicrosDurationU32,
+    pub release: MicrosDurationU32,
+}
+
+impl DebounceTiming {
+    pub const fn symmetric(duration: MicrosDurationU32) -> Self {
+        DebounceTiming {
+            press: duration,
+            release: duration,
+        }
+    }
+
+    const fn towards(&self, pressed: bool) -> MicrosDurationU32 {
+        if pressed {
+            self.press
+        } else {
+            self.release
+        }
+    }
+
+    const fn longest(&self) -> MicrosDurationU32 {
+        if self.press.ticks() > self.release.ticks() {
+            self.press
+        } else {
+            self.release
+        }
+    }
+}
+
+/// Per-key debounce timings, laid out `[row][col]` like the matrix
+#[derive(Copy, Clone)]
+pub struct DebounceConfig<const NROW: usize, const NCOL: usize> {
+    timings: [[DebounceTiming; NCOL]; NROW],
+}
+
+impl<const NROW: usize, const NCOL: usize> DebounceConfig<NROW, NCOL> {
+    pub const fn uniform(timing: DebounceTiming) -> Self {
+        DebounceConfig {
+            timings: [[timing; NCOL]; NROW],
+        }
+    }
+
+    /// Overrides the timing of a single key, e.g. for a worn switch that chatters for longer
+    pub const fn with_key(mut self, row: usize, col: usize, timing: DebounceTiming) -> Self {
+        self.timings[row][col] = timing;
+        self
+    }
+
+    pub const fn timing(&self, row: usize, col: usize) -> DebounceTiming {
+        self.timings[row][col]
+    }
+}
+
+pub trait Debouncer<const NROW: usize, const NCOL: usize> {
+    /// Feeds a raw sample of a single key and returns its debounced state
+    fn debounce(&mut self, row: usize, col: usize, raw: bool, now: Instant) -> bool;
+}
+
+/// The debouncing algorithms to choose from in constants.rs
+#[derive(Copy, Clone)]
+// Only the one chosen there is ever constructed
+#[allow(dead_code)]
+pub enum DebounceAlgorithm {
+    EagerPerKey,
+    DeferredPerKey,
+    SymmetricPerRow,
+}
+
+/// Whichever debouncer was chosen, so the choice can be made with a constant
+pub enum AnyDebouncer<const NROW: usize, const NCOL: usize> {
+    EagerPerKey(EagerPerKeyDebouncer<NROW, NCOL>),
+    DeferredPerKey(DeferredPerKeyDebouncer<NROW, NCOL>),
+    SymmetricPerRow(SymmetricPerRowDebouncer<NROW, NCOL>),
+}
+
+impl<const NROW: usize, const NCOL: usize> AnyDebouncer<NROW, NCOL> {
+    pub const fn new(algorithm: DebounceAlgorithm, config: DebounceConfig<NROW, NCOL>) -> Self {
+        match algorithm {
+            DebounceAlgorithm::EagerPerKey => {
+                AnyDebouncer::EagerPerKey(EagerPerKeyDebouncer::new(config))
+            }
+            DebounceAlgorithm::DeferredPerKey => {
+                AnyDebouncer::DeferredPerKey(DeferredPerKeyDebouncer::new(config))
+            }
+            DebounceAlgorithm::SymmetricPerRow => {
+                AnyDebouncer::SymmetricPerRow(SymmetricPerRowDebouncer::new(config))
+            }
+        }
+    }
+}
+
+impl<const NROW: usize, const NCOL: usize> Debouncer<NROW, NCOL> for AnyDebouncer<NROW, NCOL> {
+    fn debounce(&mut self, row: usize, col: usize, raw: bool, now: Instant) -> bool {
+        match self {
+            AnyDebouncer::EagerPerKey(debouncer) => debouncer.debounce(row, col, raw, now),
+            AnyDebouncer::DeferredPerKey(debouncer) => debouncer.debounce(row, col, raw, now),
+            AnyDebouncer::SymmetricPerRow(debouncer) => debouncer.debounce(row, col, raw, now),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct EagerKeyState {
+    stable: bool,
+    locked_until: Option<Instant>,
+}
+
+/// Reports a change as soon as it is seen, then ignores the key until it has had time to settle.
+/// Lowest latency, but a single noise spike is enough to register a key.
+pub struct EagerPerKeyDebouncer<const NROW: usize, const NCOL: usize> {
+    config: DebounceConfig<NROW, NCOL>,
+    keys: [[EagerKeyState; NCOL]; NROW],
+}
+
+impl<const NROW: usize, const NCOL: usize> EagerPerKeyDebouncer<NROW, NCOL> {
+    pub const fn new(config: DebounceConfig<NROW, NCOL>) -> Self {
+        EagerPerKeyDebouncer {
+            config,
+            keys: [[EagerKeyState {
+                stable: false,
+                locked_until: None,
+            }; NCOL]; NROW],
+        }
+    }
+}
+
+impl<const NROW: usize, const NCOL: usize> Debouncer<NROW, NCOL>
+    for EagerPerKeyDebouncer<NROW, NCOL>
+{
+    fn debounce(&mut self, row: usize, col: usize, raw: bool, now: Instant) -> bool {
+        let key = &mut self.keys[row][col];
+
+        match key.locked_until {
+            Some(until) if now < until => return key.stable,
+            _ => key.locked_until = None,
+        }
+
+        if raw != key.stable {
+            key.stable = raw;
+            key.locked_until = Some(now + self.config.timing(row, col).towards(raw));
+        }
+
+        key.stable
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DeferredKeyState {
+    stable: bool,
+    changed_at: Option<Instant>,
+}
+
+/// Only reports a change once the key has read the same for the whole settling time.
+/// Immune to noise, at the cost of adding the settling time to every change.
+pub struct DeferredPerKeyDebouncer<const NROW: usize, const NCOL: usize> {
+    config: DebounceConfig<NROW, NCOL>,
+    keys: [[DeferredKeyState; NCOL]; NROW],
+}
+
+impl<const NROW: usize, const NCOL: usize> DeferredPerKeyDebouncer<NROW, NCOL> {
+    pub const fn new(config: DebounceConfig<NROW, NCOL>) -> Self {
+        DeferredPerKeyDebouncer {
+            config,
+            keys: [[DeferredKeyState {
+                stable: false,
+                changed_at: None,
+            }; NCOL]; NROW],
+        }
+    }
+}
+
+impl<const NROW: usize, const NCOL: usize> Debouncer<NROW, NCOL>
+    for DeferredPerKeyDebouncer<NROW, NCOL>
+{
+    fn debounce(&mut self, row: usize, col: usize, raw: bool, now: Instant) -> bool {
+        let key = &mut self.keys[row][col];
+
+        if raw == key.stable {
+            // Any bounce back restarts the wait
+            key.changed_at = None;
+        } else {
+            let changed_at = *key.changed_at.get_or_insert(now);
+
+            if now >= changed_at + self.config.timing(row, col).towards(raw) {
+                key.stable = raw;
+                key.changed_at = None;
+            }
+        }
+
+        key.stable
+    }
+}
+
+/// Commits a whole row at once after none of its keys have changed for the settling time.
+/// Uses the longest timing configured for any key in the row, in both directions.
+pub struct SymmetricPerRowDebouncer<const NROW: usize, const NCOL: usize> {
+    timings: [MicrosDurationU32; NROW],
+    raw: [[bool; NCOL]; NROW],
+    stable: [[bool; NCOL]; NROW],
+    changed_at: [Option<Instant>; NROW],
+}
+
+impl<const NROW: usize, const NCOL: usize> SymmetricPerRowDebouncer<NROW, NCOL> {
+    pub const fn new(config: DebounceConfig<NROW, NCOL>) -> Self {
+        let mut timings = [MicrosDurationU32::from_ticks(0); NROW];
+
+        let mut row = 0;
+        while row < NROW {
+            let mut col = 0;
+            while col < NCOL {
+                let longest = config.timing(row, col).longest();
+                if longest.ticks() > timings[row].ticks() {
+                    timings[row] = longest;
+                }
+
+                col += 1;
+            }
+
+            row += 1;
+        }
+
+        SymmetricPerRowDebouncer {
+            timings,
+            raw: [[false; NCOL]; NROW],
+            stable: [[false; NCOL]; NROW],
+            changed_at: [None; NROW],
+        }
+    }
+}
+
+impl<const NROW: usize, const NCOL: usize> Debouncer<NROW, NCOL>
+    for SymmetricPerRowDebouncer<NROW, NCOL>
+{
+    fn debounce(&mut self, row: usize, col: usize, raw: bool, now: Instant) -> bool {
+        if self.raw[row][col] != raw {
+            self.raw[row][col] = raw;
+            self.changed_at[row] = Some(now);
+        }
+
+        if self.changed_at[row].is_some_and(|changed_at| now >= changed_at + self.timings[row]) {
+            self.stable[row] = self.raw[row];
+            self.changed_at[row] = None;
+        }
+
+        self.stable[row][col]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How far apart the samples in a waveform are, as the matrix is scanned
+    const SAMPLE_MICROS: u64 = 250;
+    /// Four samples
+    const SETTLE: MicrosDurationU32 = MicrosDurationU32::millis(1);
+
+    fn config<const NROW: usize, const NCOL: usize>() -> DebounceConfig<NROW, NCOL> {
+        DebounceConfig::uniform(DebounceTiming::symmetric(SETTLE))
+    }
+
+    /// Feeds waveforms for the keys of a row side by side, a character a sample, and returns the
+    /// debounced state of each key at every sample in the same form
+    fn run<const NCOL: usize>(
+        debouncer: &mut impl Debouncer<1, NCOL>,
+        waveforms: [&str; NCOL],
+    ) -> [String; NCOL] {
+        let mut debounced = [const { String::new() }; NCOL];
+
+        for sample in 0..waveforms[0].len() {
+            let now = Instant::from_ticks(sample as u64 * SAMPLE_MICROS);
+            for col in 0..NCOL {
+                let raw = waveforms[col].as_bytes()[sample] == b'1';
+                let stable = debouncer.debounce(0, col, raw, now);
+                debounced[col].push(if stable { '1' } else { '0' });
+            }
+        }
+
+        debounced
+    }
+
+    #[test]
+    fn eager_reports_the_first_edge_and_ignores_the_bounce() {
+        let mut debouncer = EagerPerKeyDebouncer::<1, 1>::new(config());
+        let [output] = run(&mut debouncer, ["0010101111111101010000000"]);
+        assert_eq!(output, "0011111111111100000000000");
+    }
+
+    #[test]
+    fn eager_registers_a_single_spike() {
+        let mut debouncer = EagerPerKeyDebouncer::<1, 1>::new(config());
+        let [output] = run(&mut debouncer, ["0001000000"]);
+        assert_eq!(output, "0001111000");
+    }
+
+    #[test]
+    fn deferred_waits_for_the_bounce_to_stop() {
+        let mut debouncer = DeferredPerKeyDebouncer::<1, 1>::new(config());
+        let [output] = run(&mut debouncer, ["0010101111111101010000000"]);
+        assert_eq!(output, "0000000000111111111111000");
+    }
+
+    #[test]
+    fn deferred_ignores_a_single_spike() {
+        let mut debouncer = DeferredPerKeyDebouncer::<1, 1>::new(config());
+        let [output] = run(&mut debouncer, ["0001000000"]);
+        assert_eq!(output, "0000000000");
+    }
+
+    #[test]
+    fn per_key_timing_only_changes_that_key() {
+        let config = config::<1, 2>().with_key(
+            0,
+            1,
+            DebounceTiming {
+                press: MicrosDurationU32::millis(2),
+                release: SETTLE,
+            },
+        );
+        let mut debouncer = DeferredPerKeyDebouncer::new(config);
+        let output = run(
+            &mut debouncer,
+            ["01111111111100000000", "01111111111100000000"],
+        );
+        assert_eq!(output, ["00000111111111110000", "00000000011111110000"]);
+    }
+
+    #[test]
+    fn symmetric_row_waits_for_the_whole_row_to_settle() {
+        let mut debouncer = SymmetricPerRowDebouncer::<1, 2>::new(config());
+        let output = run(
+            &mut debouncer,
+            ["01111111111111110000000", "00001010111111111111111"],
+        );
+        assert_eq!(
+            output,
+            ["00000000000011111111000", "00000000000011111111111"]
+        );
+    }
+
+    #[test]
+    fn symmetric_row_uses_the_longest_timing_in_the_row() {
+        let config = config::<1, 2>().with_key(
+            0,
+            1,
+            DebounceTiming {
+                press: SETTLE,
+                release: MicrosDurationU32::millis(2),
+            },
+        );
+        let mut debouncer = SymmetricPerRowDebouncer::new(config);
+        let output = run(&mut debouncer, ["011111111111100", "000000000000000"]);
+        assert_eq!(output, ["000000000111111", "000000000000000"]);
+    }
+
+    #[test]
+    fn any_debouncer_runs_the_chosen_algorithm() {
+        let waveform = ["0001000000"];
+
+        let mut eager = AnyDebouncer::<1, 1>::new(DebounceAlgorithm::EagerPerKey, config());
+        assert_eq!(run(&mut eager, waveform), ["0001111000"]);
+
+        let mut deferred = AnyDebouncer::<1, 1>::new(DebounceAlgorithm::DeferredPerKey, config());
+        assert_eq!(run(&mut deferred, waveform), ["0000000000"]);
+
+        let mut row = AnyDebouncer::<1, 1>::new(DebounceAlgorithm::SymmetricPerRow, config());
+        assert_eq!(run(&mut row, waveform), ["0000000000"]);
+    }
+}
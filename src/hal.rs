@@ -1,7 +1,5 @@
 pub use rp2040_hal as hal;
 
-pub use hal::entry;
-
 #[unsafe(link_section = ".boot2")]
 #[unsafe(no_mangle)]
 #[used]
@@ -0,0 +1,1694 @@
+mod auto_shift;
+mod caps_word;
+mod combo;
+mod dynamic_keymap;
+mod dynamic_macros;
+mod features;
+mod key_override;
+mod leader;
+mod macro_buffer;
+mod macros;
+mod mouse_keys;
+mod one_shot;
+mod tap_dance;
+mod tap_hold;
+mod unicode;
+
+use crate::common::Queue;
+use crate::constants::{
+    DYNAMIC_KEYMAP_LAYERS, DYNAMIC_MACRO_SLOTS, MAX_COMBOS, MAX_KEY_OVERRIDES, MAX_TAP_DANCES,
+    REPORT_QUEUE_SIZE, TAPPING_TERM, TAP_HOLD_BUFFER_SIZE, VIA_MACRO_COUNT,
+};
+use crate::keyboard::KeyEvent;
+use crate::report::{
+    ConsumerReport, ConsumerUsage, KeyboardReport, Modifiers, MouseReport, SystemReport,
+    SystemUsage,
+};
+use core::marker::PhantomData;
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use usbd_human_interface_device::page::Keyboard;
+
+pub use auto_shift::AutoShiftConfig;
+use auto_shift::PendingAutoShift;
+use caps_word::CapsWord;
+pub use combo::Combo;
+use combo::{ComboOutput, ComboState};
+use dynamic_keymap::DynamicKeymap;
+use dynamic_macros::DynamicMacros;
+use features::Features;
+use key_override::ActiveOverride;
+pub use key_override::KeyOverride;
+pub use leader::LeaderSequence;
+use leader::{LeaderOutcome, LeaderState};
+pub use macro_buffer::MacroBuffer;
+pub use macros::{Macro, MacroStep};
+use macros::{MacroPlayer, MacroSource};
+use mouse_keys::MouseKeys;
+pub use mouse_keys::{AccelerationCurve, MouseButton, MouseDirection, MouseKeysConfig};
+use one_shot::OneShot;
+pub use tap_dance::TapDance;
+use tap_dance::{PendingTapDance, TapDanceOutcome};
+pub use tap_hold::TapHoldConfig;
+use tap_hold::{PendingTapHold, TapHoldDecision};
+pub use unicode::UnicodeMode;
+
+/// The most layers a keymap can declare, bounded by the width of the layer masks
+pub const MAX_LAYERS: usize = u32::BITS as usize;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Does nothing, and stops lower layers from being looked at
+    NoOp,
+    /// Falls through to the next active layer below
+    Transparent,
+    Key(Keyboard),
+    Modifiers(Modifiers),
+    /// A key pressed together with some modifiers, like Shift+; for a colon
+    ModifiedKey(Modifiers, Keyboard),
+    /// Activates a layer for as long as the key is held
+    MomentaryLayer(u8),
+    /// Flips a layer on or off on every press
+    ToggleLayer(u8),
+    /// Replaces the layer everything else is stacked on top of
+    DefaultLayer(u8),
+    /// Keeps the highest active layer on after the key that activated it is released.
+    /// Pressing it again while that layer is locked unlocks it.
+    LayerLock,
+    /// Modifiers when held, a key when tapped
+    ModTap(Modifiers, Keyboard),
+    /// A momentary layer when held, a key when tapped
+    LayerTap(u8, Keyboard),
+    /// The tap dance at this index in the keymap's tap dances
+    TapDance(u8),
+    /// Plays the macro at this index in the keymap's macros
+    Macro(u8),
+    /// Starts recording a macro into a slot, or stops recording if already going
+    RecordMacro(u8),
+    StopRecording,
+    /// Plays back the macro recorded into a slot
+    PlayRecording(u8),
+    /// Plays the macro at this index in the buffer edited from VIA
+    ViaMacro(u8),
+    /// Starts typing one of the keymap's leader sequences
+    Leader,
+    /// Turns Auto Shift off or back on
+    ToggleAutoShift,
+    /// Shifts letters until the end of the word being typed
+    CapsWord,
+    /// Moves the cursor, speeding up the longer it is held
+    MouseMove(MouseDirection),
+    MouseWheel(MouseDirection),
+    MouseButton(MouseButton),
+    /// A media, volume or other consumer control key
+    Consumer(ConsumerUsage),
+    /// Powers down, suspends or wakes the host
+    System(SystemUsage),
+    /// Escape, or ` while Shift or GUI is held
+    GraveEscape,
+    /// Modifiers while held, which also type a key when tapped on their own, like ( from Shift
+    SpaceCadet(Modifiers, Keyboard),
+    /// Switches between reporting every key held and at most six, for KVMs that lose keys past the
+    /// sixth. Only the number of keys changes, as hosts that can't read the NKRO report at all ask
+    /// for the boot report instead.
+    ToggleNkro,
+    /// Types a character through the host's Unicode input method
+    Unicode(char),
+    /// Changes how Unicode characters are typed to suit the host
+    SetUnicodeMode(UnicodeMode),
+    /// Modifiers for the next key pressed when tapped, or for as long as the key is held
+    OneShotModifiers(Modifiers),
+    /// A layer for the next key pressed when tapped, or for as long as the key is held
+    OneShotLayer(u8),
+}
+
+/// Something the keymap can see pressed, either a key in the matrix or a virtual one like a combo
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyId {
+    Matrix { row: u8, col: u8 },
+    Combo(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Event {
+    pub key: KeyId,
+    pub pressed: bool,
+    pub time: Instant,
+}
+
+impl From<KeyEvent> for Event {
+    fn from(event: KeyEvent) -> Self {
+        Event {
+            key: KeyId::Matrix {
+                row: event.row,
+                col: event.col,
+            },
+            pressed: event.pressed,
+            time: event.time,
+        }
+    }
+}
+
+pub trait KeyMap<const NROW: usize, const NCOL: usize> {
+    const LAYERS: usize;
+    const COMBOS: &'static [Combo] = &[];
+    const TAP_DANCES: &'static [TapDance] = &[];
+    const MACROS: &'static [Macro] = &[];
+    const LEADER_SEQUENCES: &'static [LeaderSequence] = &[];
+    const KEY_OVERRIDES: &'static [KeyOverride] = &[];
+    const AUTO_SHIFT: Option<AutoShiftConfig> = None;
+    const MOUSE_KEYS: MouseKeysConfig = MouseKeysConfig::DEFAULT;
+
+    fn action(layer: usize, row: u8, col: u8) -> Action;
+
+    fn tap_hold_config(_row: u8, _col: u8) -> TapHoldConfig {
+        TapHoldConfig::DEFAULT
+    }
+}
+
+pub struct LayerState {
+    default: u8,
+    toggled: u32,
+    locked: u32,
+    momentary: [u8; MAX_LAYERS],
+}
+
+impl LayerState {
+    pub const fn new() -> Self {
+        LayerState {
+            default: 0,
+            toggled: 0,
+            locked: 0,
+            momentary: [0; MAX_LAYERS],
+        }
+    }
+
+    pub fn active(&self) -> u32 {
+        let momentary = self
+            .momentary
+            .iter()
+            .enumerate()
+            .filter(|(_, holds)| **holds > 0)
+            .fold(0, |mask, (layer, _)| mask | 1 << layer);
+
+        1 << self.default | self.toggled | self.locked | momentary
+    }
+
+    pub fn is_active(&self, layer: usize) -> bool {
+        self.active() & (1 << layer) != 0
+    }
+
+    pub fn highest(&self) -> u8 {
+        (u32::BITS - 1 - self.active().leading_zeros()) as u8
+    }
+
+    pub fn hold(&mut self, layer: u8) {
+        self.momentary[layer as usize] = self.momentary[layer as usize].saturating_add(1);
+    }
+
+    pub fn release(&mut self, layer: u8) {
+        self.momentary[layer as usize] = self.momentary[layer as usize].saturating_sub(1);
+    }
+
+    pub fn toggle(&mut self, layer: u8) {
+        self.toggled ^= 1 << layer;
+    }
+
+    pub fn set_default(&mut self, layer: u8) {
+        self.default = layer;
+    }
+
+    pub fn toggle_lock(&mut self) {
+        let layer = self.highest();
+
+        if self.locked & (1 << layer) != 0 {
+            // Unlocking should turn the layer off, however it was activated
+            self.locked &= !(1 << layer);
+            self.toggled &= !(1 << layer);
+        } else if layer != self.default {
+            self.locked |= 1 << layer;
+        }
+    }
+}
+
+/// A key whose action can't be known until more events or time have passed
+#[derive(Copy, Clone)]
+enum Pending {
+    TapHold(PendingTapHold),
+    TapDance(PendingTapDance),
+    AutoShift(PendingAutoShift),
+}
+
+/// Turns key events into HID output, tracking what each held key resolved to on press so that
+/// releasing it undoes the same thing even if the layers have changed in between
+pub struct KeymapEngine<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>> {
+    layers: LayerState,
+    // Keys remapped from VIA, looked at before the keymap's own
+    dynamic_keymap: DynamicKeymap<NROW, NCOL>,
+    held: [[Option<Action>; NCOL]; NROW],
+    held_combos: [Option<Action>; MAX_COMBOS],
+    report: KeyboardReport,
+    // Every change to the report is queued, so a tap resolved within one scan is still seen
+    reports: Queue<KeyboardReport, REPORT_QUEUE_SIZE>,
+    mouse: MouseKeys,
+    mouse_reports: Queue<MouseReport, REPORT_QUEUE_SIZE>,
+    consumer: ConsumerReport,
+    consumer_reports: Queue<ConsumerReport, REPORT_QUEUE_SIZE>,
+    system: SystemReport,
+    system_reports: Queue<SystemReport, REPORT_QUEUE_SIZE>,
+    combos: ComboState,
+    // The combos, tap dances and key overrides, which can be changed from Vial
+    features: Features,
+    pending: Option<Pending>,
+    // Events that arrived while a key was undecided
+    buffered: Queue<Event, TAP_HOLD_BUFFER_SIZE>,
+    macros: MacroPlayer,
+    recordings: DynamicMacros,
+    macro_buffer: MacroBuffer,
+    leader: LeaderState,
+    auto_shift: bool,
+    // Saved to flash along with the Unicode mode
+    unicode_mode: UnicodeMode,
+    nkro: bool,
+    caps_word: CapsWord,
+    key_override: Option<ActiveOverride>,
+    // What the Grave-Escape key sent when pressed, so it releases the same key
+    grave_escape: Keyboard,
+    // The Space Cadet key held, if nothing else has been pressed since
+    space_cadet: Option<(Action, Instant)>,
+    one_shot: OneShot,
+    // The time of the latest event or tick, for actions that care when they happen
+    now: Instant,
+    _keymap: PhantomData<M>,
+}
+
+impl<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>> KeymapEngine<NROW, NCOL, M> {
+    pub const fn new() -> Self {
+        KeymapEngine {
+            layers: LayerState::new(),
+            dynamic_keymap: DynamicKeymap::new(),
+            held: [[None; NCOL]; NROW],
+            held_combos: [None; MAX_COMBOS],
+            report: KeyboardReport::new(),
+            reports: Queue::new(),
+            mouse: MouseKeys::new(),
+            mouse_reports: Queue::new(),
+            consumer: ConsumerReport::new(),
+            consumer_reports: Queue::new(),
+            system: SystemReport::new(),
+            system_reports: Queue::new(),
+            combos: ComboState::new(),
+            features: Features::new(M::COMBOS, M::TAP_DANCES, M::KEY_OVERRIDES),
+            pending: None,
+            buffered: Queue::new(),
+            macros: MacroPlayer::new(),
+            recordings: DynamicMacros::new(),
+            macro_buffer: MacroBuffer::new(),
+            leader: LeaderState::new(),
+            auto_shift: true,
+            unicode_mode: UnicodeMode::DEFAULT,
+            nkro: true,
+            caps_word: CapsWord::new(),
+            key_override: None,
+            grave_escape: Keyboard::Escape,
+            space_cadet: None,
+            one_shot: OneShot::new(),
+            now: Instant::from_ticks(0),
+            _keymap: PhantomData,
+        }
+    }
+
+    pub fn next_report(&mut self) -> Option<KeyboardReport> {
+        self.reports.pop()
+    }
+
+    pub fn next_mouse_report(&mut self) -> Option<MouseReport> {
+        self.mouse_reports.pop()
+    }
+
+    pub fn next_consumer_report(&mut self) -> Option<ConsumerReport> {
+        self.consumer_reports.pop()
+    }
+
+    pub fn next_system_report(&mut self) -> Option<SystemReport> {
+        self.system_reports.pop()
+    }
+
+    pub fn is_caps_word_active(&self) -> bool {
+        self.caps_word.is_active()
+    }
+
+    /// Whether reports can hold every key held, or only six like a boot keyboard
+    pub fn is_nkro(&self) -> bool {
+        self.nkro
+    }
+
+    pub fn set_nkro(&mut self, nkro: bool) {
+        self.nkro = nkro;
+    }
+
+    /// How Unicode characters are typed, as last picked with a key
+    pub fn unicode_mode(&self) -> UnicodeMode {
+        self.unicode_mode
+    }
+
+    pub fn set_unicode_mode(&mut self, mode: UnicodeMode) {
+        self.unicode_mode = mode;
+    }
+
+    /// How many of the keymap's layers can be remapped while running
+    pub fn dynamic_layer_count(&self) -> usize {
+        M::LAYERS.min(DYNAMIC_KEYMAP_LAYERS)
+    }
+
+    /// The action of a key on one of the layers that can be remapped, if there is such a key
+    pub fn keymap_action(&self, layer: usize, row: u8, col: u8) -> Option<Action> {
+        let exists =
+            layer < self.dynamic_layer_count() && (row as usize) < NROW && (col as usize) < NCOL;
+        exists.then(|| self.action(layer, row, col))
+    }
+
+    /// Remaps a key, returning whether it is on a layer that can be remapped. Keys already held
+    /// keep doing what they did when pressed until released.
+    pub fn set_keymap_action(&mut self, layer: usize, row: u8, col: u8, action: Action) -> bool {
+        layer < self.dynamic_layer_count() && self.dynamic_keymap.set(layer, row, col, action)
+    }
+
+    /// What a key has been remapped to, leaving out keys that are still the keymap's own
+    pub fn remapped_action(&self, layer: usize, row: u8, col: u8) -> Option<Action> {
+        self.dynamic_keymap.get(layer, row, col)
+    }
+
+    /// Undoes every remapping
+    pub fn reset_keymap(&mut self) {
+        self.dynamic_keymap.reset();
+    }
+
+    pub fn combo(&self, index: usize) -> Option<&Combo> {
+        self.features.combos.get(index)
+    }
+
+    /// Replaces a combo, returning whether there is room for one at that index
+    pub fn set_combo(&mut self, index: usize, combo: Combo) -> bool {
+        self.features
+            .combos
+            .get_mut(index)
+            .map(|entry| *entry = combo)
+            .is_some()
+    }
+
+    pub fn tap_dance(&self, index: usize) -> Option<&TapDance> {
+        self.features.tap_dances.get(index)
+    }
+
+    /// Replaces a tap dance, returning whether there is room for one at that index
+    pub fn set_tap_dance(&mut self, index: usize, tap_dance: TapDance) -> bool {
+        self.features
+            .tap_dances
+            .get_mut(index)
+            .map(|entry| *entry = tap_dance)
+            .is_some()
+    }
+
+    pub fn key_override(&self, index: usize) -> Option<&KeyOverride> {
+        self.features.key_overrides.get(index)
+    }
+
+    /// Replaces a key override, returning whether there is room for one at that index
+    pub fn set_key_override(&mut self, index: usize, key_override: KeyOverride) -> bool {
+        self.features
+            .key_overrides
+            .get_mut(index)
+            .map(|entry| *entry = key_override)
+            .is_some()
+    }
+
+    /// Puts the combos, tap dances and key overrides back to the keymap's own
+    pub fn reset_features(&mut self) {
+        self.features = Features::new(M::COMBOS, M::TAP_DANCES, M::KEY_OVERRIDES);
+    }
+
+    pub fn macro_buffer(&self) -> &MacroBuffer {
+        &self.macro_buffer
+    }
+
+    pub fn macro_buffer_mut(&mut self) -> &mut MacroBuffer {
+        &mut self.macro_buffer
+    }
+
+    pub fn process(&mut self, event: KeyEvent) {
+        let mut output = ComboOutput::new();
+        self.combos
+            .process(&self.features.combos, event, &mut output);
+
+        while let Some(event) = output.pop() {
+            self.process_event(event);
+        }
+    }
+
+    /// Resolves anything that was waiting on time passing
+    pub fn tick(&mut self, now: Instant) {
+        self.now = now;
+
+        let mut output = ComboOutput::new();
+        self.combos.tick(&self.features.combos, now, &mut output);
+
+        while let Some(event) = output.pop() {
+            self.process_event(event);
+        }
+
+        match self.pending {
+            Some(Pending::TapHold(pending)) if pending.has_expired(now) => {
+                self.resolve_tap_hold(TapHoldDecision::Hold)
+            }
+            Some(Pending::TapDance(pending))
+                if pending.has_expired(&self.features.tap_dances, now) =>
+            {
+                self.resolve_tap_dance()
+            }
+            Some(Pending::AutoShift(pending))
+                if M::AUTO_SHIFT.is_some_and(|config| pending.has_expired(&config, now)) =>
+            {
+                self.resolve_auto_shift(true)
+            }
+            _ => {}
+        }
+
+        if let Some(action) = self.leader.expire(M::LEADER_SEQUENCES, now) {
+            self.press(action);
+            self.release(action);
+        }
+
+        if let Some(report) = self.mouse.tick(&M::MOUSE_KEYS, now) {
+            self.mouse_reports.push_latest(report);
+        }
+
+        if self.caps_word.expire(now) {
+            self.emit_report();
+        }
+
+        if self.one_shot.expire(now, &mut self.layers) {
+            self.emit_report();
+        }
+
+        // Only move a macro on once everything before it has been handed over to be sent
+        if self.reports.is_empty()
+            && self
+                .macros
+                .advance(M::MACROS, &self.recordings, &self.macro_buffer, now)
+        {
+            self.emit_report();
+        }
+    }
+
+    fn process_event(&mut self, event: Event) {
+        self.now = event.time;
+
+        match self.pending {
+            None => self.dispatch(event),
+            Some(Pending::TapHold(pending)) => {
+                if let Err(event) = self.buffered.push(event) {
+                    // Out of room to keep waiting
+                    self.resolve_tap_hold(TapHoldDecision::Hold);
+                    return self.process_event(event);
+                }
+
+                if let Some(decision) = pending.decide(&event, &self.buffered) {
+                    self.resolve_tap_hold(decision);
+                }
+            }
+            Some(Pending::TapDance(mut pending)) => {
+                if pending.has_expired(&self.features.tap_dances, event.time) {
+                    self.resolve_tap_dance();
+                    return self.process_event(event);
+                }
+
+                if event.key == pending.key {
+                    let finished = pending.update(&self.features.tap_dances, &event);
+                    self.pending = Some(Pending::TapDance(pending));
+
+                    if finished {
+                        self.resolve_tap_dance();
+                    }
+                } else if let Err(event) = self.buffered.push(event) {
+                    self.resolve_tap_dance();
+                    self.process_event(event);
+                } else if event.pressed {
+                    // Any other key being pressed interrupts the dance
+                    self.resolve_tap_dance();
+                }
+            }
+            Some(Pending::AutoShift(pending)) => {
+                if let Err(event) = self.buffered.push(event) {
+                    self.resolve_auto_shift(false);
+                    return self.process_event(event);
+                }
+
+                let decision = M::AUTO_SHIFT.and_then(|config| pending.decide(&config, &event));
+                if let Some(shifted) = decision {
+                    self.resolve_auto_shift(shifted);
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        if event.pressed {
+            // Whatever the key does, a one-shot key held with it is now a normal hold
+            self.one_shot.interrupt();
+        }
+
+        if event.pressed && self.leader.is_active() {
+            if let Some(key) = leader::sequence_key(self.action_of(event.key)) {
+                // The key is typed into the sequence rather than pressed, so its release does nothing
+                *self.held_mut(event.key) = Some(Action::NoOp);
+
+                if let LeaderOutcome::Matched(action) =
+                    self.leader.push(M::LEADER_SEQUENCES, key, event.time)
+                {
+                    self.press(action);
+                    self.release(action);
+                }
+                return;
+            }
+
+            // Anything that isn't a plain key gives up on the sequence and acts as normal
+            self.leader.cancel();
+        }
+
+        if event.pressed {
+            match self.action_of(event.key) {
+                action @ (Action::ModTap(..) | Action::LayerTap(..)) => {
+                    let config = match event.key {
+                        KeyId::Matrix { row, col } => M::tap_hold_config(row, col),
+                        KeyId::Combo(_) => TapHoldConfig::DEFAULT,
+                    };
+                    self.pending =
+                        Some(Pending::TapHold(PendingTapHold::new(event, action, config)));
+                }
+                // Tap dances past the end of the table do nothing, like keys with no action
+                Action::TapDance(index) if (index as usize) < MAX_TAP_DANCES => {
+                    self.pending = Some(Pending::TapDance(PendingTapDance::new(event, index)));
+                }
+                Action::Key(key) if self.auto_shifts(event.key, key) => {
+                    self.pending = Some(Pending::AutoShift(PendingAutoShift::new(event, key)));
+                }
+                action => self.hold(event.key, action),
+            }
+        } else if let Some(action) = self.held_mut(event.key).take() {
+            if self
+                .key_override
+                .is_some_and(|active| active.key == event.key)
+            {
+                self.key_override = None;
+            }
+
+            self.release(action);
+        }
+    }
+
+    fn resolve_tap_hold(&mut self, decision: TapHoldDecision) {
+        let Some(Pending::TapHold(pending)) = self.pending else {
+            return;
+        };
+        self.pending = None;
+
+        self.hold(pending.key, pending.resolve(decision));
+
+        self.replay();
+    }
+
+    fn resolve_tap_dance(&mut self) {
+        let Some(Pending::TapDance(pending)) = self.pending else {
+            return;
+        };
+        self.pending = None;
+
+        match pending.outcome(&self.features.tap_dances) {
+            TapDanceOutcome::Held(action) => {
+                *self.held_mut(pending.key) = Some(action);
+                self.press(action);
+            }
+            TapDanceOutcome::Tapped(action) => {
+                self.press(action);
+                self.release(action);
+            }
+        }
+
+        self.replay();
+    }
+
+    fn resolve_auto_shift(&mut self, shifted: bool) {
+        let Some(Pending::AutoShift(pending)) = self.pending else {
+            return;
+        };
+        self.pending = None;
+
+        let repeat = M::AUTO_SHIFT.is_some_and(|config| config.repeat);
+        let key = pending.keycode;
+
+        match (shifted, repeat) {
+            (true, false) => {
+                // Sent just once, so holding the key on doesn't repeat anything
+                let action = Action::ModifiedKey(Modifiers::LEFT_SHIFT, key);
+                *self.held_mut(pending.key) = Some(Action::NoOp);
+                self.press(action);
+                self.release(action);
+            }
+            (true, true) => {
+                let action = Action::ModifiedKey(Modifiers::LEFT_SHIFT, key);
+                *self.held_mut(pending.key) = Some(action);
+                self.press(action);
+            }
+            (false, _) => self.hold(pending.key, Action::Key(key)),
+        }
+
+        self.replay();
+    }
+
+    /// Whether pressing a key should wait to see if it is held long enough to be shifted
+    fn auto_shifts(&self, id: KeyId, key: Keyboard) -> bool {
+        // Shortcuts with other modifiers held shouldn't be slowed down or changed
+        let modifiers = self.report.modifiers().union(self.one_shot.modifiers());
+
+        self.auto_shift
+            && matches!(id, KeyId::Matrix { .. })
+            && modifiers.is_empty()
+            && M::AUTO_SHIFT.is_some_and(|config| config.applies_to(key))
+    }
+
+    /// Presses the action a key resolved to until the key is released, unless a key override
+    /// replaces it
+    fn hold(&mut self, key: KeyId, action: Action) {
+        let held = self.report.modifiers().union(self.one_shot.modifiers());
+
+        let action = match key_override::find(&self.features.key_overrides, key, action, held) {
+            Some((active, replacement)) => {
+                self.key_override = Some(active);
+                replacement
+            }
+            None => action,
+        };
+
+        *self.held_mut(key) = Some(action);
+        self.press(action);
+    }
+
+    /// Feeds back the events that were held up by a pending key once it is resolved
+    fn replay(&mut self) {
+        // Replaying can start waiting on another key, which buffers into a fresh queue
+        let mut replay = core::mem::replace(&mut self.buffered, Queue::new());
+        while let Some(event) = replay.pop() {
+            self.process_event(event);
+        }
+    }
+
+    fn held_mut(&mut self, key: KeyId) -> &mut Option<Action> {
+        match key {
+            KeyId::Matrix { row, col } => &mut self.held[row as usize][col as usize],
+            KeyId::Combo(index) => &mut self.held_combos[index as usize],
+        }
+    }
+
+    fn action_of(&self, key: KeyId) -> Action {
+        match key {
+            KeyId::Matrix { row, col } => self.resolve(row, col),
+            KeyId::Combo(index) => self.features.combos[index as usize].action,
+        }
+    }
+
+    /// Looks up the action of a key on the highest active layer that isn't transparent
+    fn resolve(&self, row: u8, col: u8) -> Action {
+        (0..M::LAYERS)
+            .rev()
+            .filter(|&layer| self.layers.is_active(layer))
+            .map(|layer| self.action(layer, row, col))
+            .find(|&action| action != Action::Transparent)
+            .unwrap_or(Action::NoOp)
+    }
+
+    fn action(&self, layer: usize, row: u8, col: u8) -> Action {
+        self.dynamic_keymap
+            .get(layer, row, col)
+            .unwrap_or_else(|| M::action(layer, row, col))
+    }
+
+    fn press(&mut self, action: Action) {
+        // Modifiers and layers go along with whatever's armed, for the next key to use
+        let uses_one_shot = !matches!(
+            action,
+            Action::NoOp
+                | Action::Transparent
+                | Action::Modifiers(_)
+                | Action::SpaceCadet(..)
+                | Action::OneShotModifiers(_)
+                | Action::OneShotLayer(_)
+                | Action::MomentaryLayer(_)
+                | Action::ToggleLayer(_)
+                | Action::DefaultLayer(_)
+                | Action::LayerLock
+                | Action::ModTap(..)
+                | Action::LayerTap(..)
+                | Action::TapDance(_)
+        );
+
+        // Anything pressed while a Space Cadet key is held makes it a plain modifier
+        self.space_cadet = None;
+
+        match action {
+            Action::NoOp | Action::Transparent => {}
+            Action::Key(key) => {
+                self.caps_word.press(key, self.now);
+                self.report.press(key);
+                self.emit_report();
+            }
+            Action::Modifiers(modifiers) => {
+                self.report.press_modifiers(modifiers);
+                self.emit_report();
+            }
+            Action::ModifiedKey(modifiers, key) => {
+                self.caps_word.press(key, self.now);
+                self.report.press_modifiers(modifiers);
+                self.report.press(key);
+                self.emit_report();
+            }
+            Action::OneShotModifiers(modifiers) => {
+                self.one_shot.press();
+                self.report.press_modifiers(modifiers);
+                self.emit_report();
+            }
+            Action::OneShotLayer(layer) => {
+                self.one_shot.press();
+                self.layers.hold(layer);
+            }
+            Action::MomentaryLayer(layer) => self.layers.hold(layer),
+            Action::ToggleLayer(layer) => self.layers.toggle(layer),
+            Action::DefaultLayer(layer) => self.layers.set_default(layer),
+            Action::LayerLock => self.layers.toggle_lock(),
+            // Like keys with no action, macros past the end of their table do nothing
+            Action::Macro(index) => {
+                if (index as usize) < M::MACROS.len() {
+                    self.macros.play(MacroSource::Static(index));
+                }
+            }
+            Action::ViaMacro(index) => {
+                if index < VIA_MACRO_COUNT {
+                    self.macros.play(MacroSource::Buffer(index));
+                }
+            }
+            Action::RecordMacro(slot) => {
+                if self.recordings.is_recording() {
+                    self.recordings.stop();
+                } else {
+                    self.recordings.start(slot, &self.report);
+                }
+            }
+            Action::StopRecording => self.recordings.stop(),
+            Action::Leader => self.leader.start(self.now),
+            Action::ToggleAutoShift => self.auto_shift = !self.auto_shift,
+            Action::CapsWord => {
+                self.caps_word.toggle(self.now);
+                self.emit_report();
+            }
+            Action::MouseMove(direction) => self.mouse.start_moving(direction, self.now),
+            Action::MouseWheel(direction) => self.mouse.start_scrolling(direction, self.now),
+            Action::MouseButton(button) => {
+                self.mouse.press_button(button);
+                self.mouse_reports.push_latest(self.mouse.report());
+            }
+            Action::Consumer(usage) => {
+                self.consumer.press(usage);
+                self.consumer_reports.push_latest(self.consumer);
+            }
+            Action::System(control) => {
+                self.system.press(control);
+                self.system_reports.push_latest(self.system);
+            }
+            Action::GraveEscape => {
+                let modifiers = self.report.modifiers().union(self.one_shot.modifiers());
+                let shift_or_gui = Modifiers::LEFT_SHIFT
+                    .union(Modifiers::RIGHT_SHIFT)
+                    .union(Modifiers::LEFT_GUI)
+                    .union(Modifiers::RIGHT_GUI);
+
+                self.grave_escape = if modifiers.intersection(shift_or_gui).is_empty() {
+                    Keyboard::Escape
+                } else {
+                    Keyboard::Grave
+                };
+                self.report.press(self.grave_escape);
+                self.emit_report();
+            }
+            Action::SpaceCadet(modifiers, _) => {
+                self.report.press_modifiers(modifiers);
+                self.emit_report();
+                self.space_cadet = Some((action, self.now));
+            }
+            Action::Unicode(character) => {
+                let source = MacroSource::Unicode(character, self.unicode_mode);
+                self.macros.play(source);
+            }
+            Action::SetUnicodeMode(mode) => self.unicode_mode = mode,
+            Action::ToggleNkro => self.nkro = !self.nkro,
+            Action::PlayRecording(slot) => {
+                // Playing a macro into itself would never end
+                if (slot as usize) < DYNAMIC_MACRO_SLOTS && !self.recordings.is_recording_into(slot)
+                {
+                    self.macros.play(MacroSource::Recorded(slot));
+                }
+            }
+            // Only ever held once resolved
+            Action::ModTap(..) | Action::LayerTap(..) | Action::TapDance(_) => {}
+        }
+
+        // One-shot modifiers are let go of straight after the report with the key in it
+        if uses_one_shot && self.one_shot.consume(&mut self.layers) {
+            self.emit_report();
+        }
+    }
+
+    fn release(&mut self, action: Action) {
+        match action {
+            Action::Key(key) => {
+                self.report.release(key);
+                self.emit_report();
+            }
+            Action::Modifiers(modifiers) => {
+                self.report.release_modifiers(modifiers);
+                self.emit_report();
+            }
+            Action::ModifiedKey(modifiers, key) => {
+                self.report.release(key);
+                self.report.release_modifiers(modifiers);
+                self.emit_report();
+            }
+            Action::OneShotModifiers(modifiers) => {
+                self.report.release_modifiers(modifiers);
+                if self.one_shot.is_tap() {
+                    self.one_shot.tap_modifiers(modifiers, self.now);
+                }
+                self.emit_report();
+            }
+            Action::OneShotLayer(layer) => {
+                self.layers.release(layer);
+                if self.one_shot.is_tap() {
+                    self.one_shot.tap_layer(layer, self.now, &mut self.layers);
+                }
+            }
+            Action::MomentaryLayer(layer) => self.layers.release(layer),
+            Action::GraveEscape => {
+                self.report.release(self.grave_escape);
+                self.emit_report();
+            }
+            Action::SpaceCadet(modifiers, key) => {
+                let tapped = self.space_cadet.is_some_and(|(held, pressed_at)| {
+                    held == action && self.now < pressed_at + TAPPING_TERM
+                });
+                self.space_cadet = None;
+
+                if tapped {
+                    // Typed with the modifiers still held, then let go of after
+                    self.report.press(key);
+                    self.emit_report();
+                    self.report.release(key);
+                    self.emit_report();
+                }
+
+                self.report.release_modifiers(modifiers);
+                self.emit_report();
+            }
+            Action::MouseMove(direction) => self.mouse.stop_moving(direction),
+            Action::MouseWheel(direction) => self.mouse.stop_scrolling(direction),
+            Action::MouseButton(button) => {
+                self.mouse.release_button(button);
+                self.mouse_reports.push_latest(self.mouse.report());
+            }
+            Action::Consumer(usage) => {
+                self.consumer.release(usage);
+                self.consumer_reports.push_latest(self.consumer);
+            }
+            Action::System(control) => {
+                self.system.release(control);
+                self.system_reports.push_latest(self.system);
+            }
+            _ => {}
+        }
+    }
+
+    fn emit_report(&mut self) {
+        let mut report = self.report;
+        report.press_modifiers(self.one_shot.modifiers());
+        report.press_modifiers(self.caps_word.modifiers());
+        if let Some(active) = self.key_override {
+            report.release_modifiers(active.suppressed);
+        }
+
+        self.recordings.record(&report);
+
+        if self.macros.masks_modifiers(M::MACROS) {
+            report.release_modifiers(report.modifiers());
+        }
+        report.merge(self.macros.report());
+
+        self.reports.push_latest(report);
+    }
+}
+
+macro_rules! declare_keymaps {
+    { $(
+        $vv:vis struct $name:ident<$nrows:tt, $ncols:tt> {
+            $(
+                layers => $layers:expr,
+            )?
+            $(
+                layer $layer:literal => {
+                    $(
+                        $row:pat_param => {
+                            $(
+                                $col:pat => $out:expr
+                            ),* $(,)?
+                        }
+                    ),* $(,)?
+                }
+            ),* $(,)?
+            $(
+                tap_hold => {
+                    $(
+                        ($th_row:pat_param, $th_col:pat) => $th_config:expr
+                    ),* $(,)?
+                } $(,)?
+            )?
+            $(
+                combos => [
+                    $( $combo:expr ),* $(,)?
+                ] $(,)?
+            )?
+            $(
+                tap_dances => [
+                    $( $tap_dance:expr ),* $(,)?
+                ] $(,)?
+            )?
+            $(
+                macros => [
+                    $( $macro:expr ),* $(,)?
+                ] $(,)?
+            )?
+            $(
+                leader => [
+                    $( $leader:expr ),* $(,)?
+                ] $(,)?
+            )?
+            $(
+                key_overrides => [
+                    $( $key_override:expr ),* $(,)?
+                ] $(,)?
+            )?
+            $(
+                auto_shift => $auto_shift:expr,
+            )?
+            $(
+                mouse_keys => $mouse_keys:expr $(,)?
+            )?
+        }
+    ),+ } => {
+        $(
+            $vv struct $name ();
+            impl $name {
+                const INTERNAL_MAP: [[[Action; $ncols]; $nrows]; <Self as KeyMap<$nrows, $ncols>>::LAYERS] = const {
+                    let mut output = [[[Action::Transparent; $ncols]; $nrows]; <Self as KeyMap<$nrows, $ncols>>::LAYERS];
+
+                    // Whole layers from a table like the one generated from keymap.json, which
+                    // any keys declared below are put on top of
+                    $(
+                        let layers = $layers;
+                        let mut l = 0;
+
+                        while l < layers.len() {
+                            output[l] = layers[l];
+                            l += 1;
+                        }
+                    )?
+
+                    let mut l = 0;
+
+                    while l < <Self as KeyMap<$nrows, $ncols>>::LAYERS {
+                        let mut i = 0;
+
+                        while i < $ncols {
+                            let mut j = 0;
+
+                            while j < $nrows {
+                                output[l][j][i] = match (l, j, i) {
+                                    $(
+                                        $(
+                                            $(( $layer, $row, $col ) => $out,)*
+                                        )*
+                                    )*
+                                    _ => output[l][j][i]
+                                };
+
+                                j += 1;
+                            }
+
+                            i += 1;
+                        }
+
+                        l += 1;
+                    }
+
+                    output
+                };
+            }
+            impl KeyMap<$nrows, $ncols> for $name {
+                const LAYERS: usize = const {
+                    let layers = 0 $( + $layers.len() )?;
+                    $(
+                        let layers = if $layer + 1 > layers { $layer + 1 } else { layers };
+                    )*
+
+                    assert!(layers > 0, "A keymap needs at least one layer");
+                    assert!(layers <= MAX_LAYERS, "Too many layers");
+                    layers
+                };
+
+                $(
+                    const COMBOS: &'static [Combo] = const {
+                        const DECLARED: &[Combo] = &[$( $combo ),*];
+
+                        assert!(DECLARED.len() <= MAX_COMBOS, "Too many combos");
+                        DECLARED
+                    };
+                )?
+
+                $(
+                    const TAP_DANCES: &'static [TapDance] = const {
+                        const DECLARED: &[TapDance] = &[$( $tap_dance ),*];
+
+                        assert!(DECLARED.len() <= MAX_TAP_DANCES, "Too many tap dances");
+                        DECLARED
+                    };
+                )?
+
+                $(
+                    const MACROS: &'static [Macro] = &[$( $macro ),*];
+                )?
+
+                $(
+                    const LEADER_SEQUENCES: &'static [LeaderSequence] = &[$( $leader ),*];
+                )?
+
+                $(
+                    const KEY_OVERRIDES: &'static [KeyOverride] = const {
+                        const DECLARED: &[KeyOverride] = &[$( $key_override ),*];
+
+                        assert!(DECLARED.len() <= MAX_KEY_OVERRIDES, "Too many key overrides");
+                        DECLARED
+                    };
+                )?
+
+                $(
+                    const AUTO_SHIFT: Option<AutoShiftConfig> = Some($auto_shift);
+                )?
+
+                $(
+                    const MOUSE_KEYS: MouseKeysConfig = $mouse_keys;
+                )?
+
+                fn action(layer: usize, row: u8, col: u8) -> Action {
+                    Self::INTERNAL_MAP[layer][row as usize][col as usize]
+                }
+
+                $(
+                    fn tap_hold_config(row: u8, col: u8) -> TapHoldConfig {
+                        match (row, col) {
+                            $(( $th_row, $th_col ) => $th_config,)*
+                            _ => TapHoldConfig::DEFAULT,
+                        }
+                    }
+                )?
+            }
+        )+
+    }
+}
+
+// The layers from keymap.json, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/keymap.rs"));
+
+declare_keymaps! {
+    pub struct BasicKeymap<5, 15> {
+        layers => KEYMAP_LAYERS,
+        tap_hold => {
+            // CapsLock as Ctrl needs to work for quick shortcuts like Ctrl+C
+            (2, 0) => TapHoldConfig::DEFAULT.hold_on_other_key_press(),
+            // Home row mods get some slack so fast rolls while typing stay as taps
+            (2, 1..=4 | 7..=9) => TapHoldConfig::DEFAULT
+                .tapping_term(MicrosDurationU32::millis(250))
+                .permissive_hold(),
+        },
+        combos => [
+            // Escape without leaving the home row
+            Combo::new(&[(2, 7), (2, 8)], Action::Key(Keyboard::Escape)),
+            Combo::new(&[(2, 8), (2, 9)], Action::Key(Keyboard::DeleteBackspace)),
+            Combo::new(&[(2, 7), (2, 8), (2, 9)], Action::Key(Keyboard::ReturnEnter)),
+            // Both shifts together
+            Combo::new(&[(3, 0), (3, 12)], Action::CapsWord),
+        ],
+        tap_dances => [
+            // ; on a tap, : on a double tap, and the Fn layer when held
+            TapDance::new(&[
+                Action::Key(Keyboard::Semicolon),
+                Action::ModifiedKey(Modifiers::LEFT_SHIFT, Keyboard::Semicolon),
+            ])
+            .with_holds(&[Action::MomentaryLayer(1)]),
+        ],
+        macros => [
+            // Copy the whole line the cursor is on
+            Macro::new(&[
+                MacroStep::Tap(Keyboard::Home),
+                MacroStep::PressModifiers(Modifiers::LEFT_SHIFT),
+                MacroStep::Tap(Keyboard::End),
+                MacroStep::ReleaseModifiers(Modifiers::LEFT_SHIFT),
+                // Some editors take a moment to update the selection
+                MacroStep::Delay(MicrosDurationU32::millis(10)),
+                MacroStep::PressModifiers(Modifiers::LEFT_CTRL),
+                MacroStep::Tap(Keyboard::C),
+                MacroStep::ReleaseModifiers(Modifiers::LEFT_CTRL),
+            ]),
+            // Forward a word, or select it with Shift held
+            Macro::new(&[
+                MacroStep::PressModifiers(Modifiers::LEFT_CTRL),
+                MacroStep::Tap(Keyboard::RightArrow),
+                MacroStep::ReleaseModifiers(Modifiers::LEFT_CTRL),
+            ])
+            .keep_modifiers(),
+        ],
+        leader => [
+            // Fn+Space, then G C copies the current line
+            LeaderSequence::new(&[Keyboard::G, Keyboard::C], Action::Macro(0)),
+            LeaderSequence::new(
+                &[Keyboard::S],
+                Action::ModifiedKey(Modifiers::LEFT_CTRL, Keyboard::S),
+            ),
+            LeaderSequence::new(&[Keyboard::E], Action::Unicode('€')),
+            // U, then the first letter of the host OS, to type Unicode the way it expects
+            LeaderSequence::new(
+                &[Keyboard::U, Keyboard::L],
+                Action::SetUnicodeMode(UnicodeMode::Linux),
+            ),
+            LeaderSequence::new(
+                &[Keyboard::U, Keyboard::M],
+                Action::SetUnicodeMode(UnicodeMode::MacOs),
+            ),
+            LeaderSequence::new(
+                &[Keyboard::U, Keyboard::W],
+                Action::SetUnicodeMode(UnicodeMode::WinCompose),
+            ),
+        ],
+        key_overrides => [
+            KeyOverride::new(
+                Modifiers::LEFT_SHIFT.union(Modifiers::RIGHT_SHIFT),
+                Keyboard::DeleteBackspace,
+                Action::Key(Keyboard::DeleteForward),
+            ),
+            // Escape sits where ~ is on most boards
+            KeyOverride::new(
+                Modifiers::LEFT_SHIFT.union(Modifiers::RIGHT_SHIFT),
+                Keyboard::Escape,
+                Action::ModifiedKey(Modifiers::LEFT_SHIFT, Keyboard::Grave),
+            ),
+        ],
+        // Numbers are left alone so typing them in quick bursts isn't slowed down
+        auto_shift => AutoShiftConfig::DEFAULT.without_numbers(),
+        mouse_keys => MouseKeysConfig::DEFAULT.curve(AccelerationCurve::Quadratic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Engine = KeymapEngine<5, 15, BasicKeymap>;
+
+    /// Plays out key events at their times in milliseconds, ticking every millisecond until a
+    /// second after the last, and returns the keys in each report sent
+    fn run(engine: &mut Engine, events: &[(u64, u8, u8, bool)]) -> Vec<Vec<Keyboard>> {
+        let end = events.last().map_or(0, |&(millis, ..)| millis) + 1000;
+        let mut events = events.iter().peekable();
+        let mut reports = Vec::new();
+
+        for millis in 0..=end {
+            let time = Instant::from_ticks(millis * 1000);
+            while let Some(&(_, row, col, pressed)) = events.next_if(|&&(at, ..)| at == millis) {
+                engine.process(KeyEvent {
+                    row,
+                    col,
+                    pressed,
+                    time,
+                });
+            }
+            engine.tick(time);
+
+            while let Some(report) = engine.next_report() {
+                reports.push(report.keys().collect());
+            }
+        }
+
+        reports
+    }
+
+    #[test]
+    fn macros_past_the_end_of_their_tables_do_nothing() {
+        for action in [
+            Action::Macro(BasicKeymap::MACROS.len() as u8),
+            Action::PlayRecording(DYNAMIC_MACRO_SLOTS as u8),
+            Action::ViaMacro(VIA_MACRO_COUNT),
+        ] {
+            let mut engine = Engine::new();
+            engine.set_keymap_action(0, 0, 1, action);
+
+            assert!(run(&mut engine, &[(0, 0, 1, true), (50, 0, 1, false)]).is_empty());
+        }
+    }
+
+    #[test]
+    fn macros_keep_held_modifiers_only_when_asked_to() {
+        let mut engine = Engine::new();
+        engine.set_keymap_action(0, 4, 3, Action::Key(Keyboard::LeftShift));
+        engine.set_keymap_action(0, 0, 1, Action::Macro(0));
+        engine.set_keymap_action(0, 0, 2, Action::Macro(1));
+
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 4, 3, true),
+                (50, 0, 1, true),
+                (60, 0, 1, false),
+                (100, 0, 2, true),
+                (110, 0, 2, false),
+                (200, 4, 3, false),
+            ],
+        );
+
+        // Shift is hidden while the line is copied, but selects the word skipped over
+        let pressing = |key| reports.iter().find(|keys| keys.contains(&key)).unwrap();
+        assert_eq!(pressing(Keyboard::Home), &[Keyboard::Home]);
+        assert_eq!(pressing(Keyboard::C), &[Keyboard::C, Keyboard::LeftControl]);
+        assert_eq!(
+            pressing(Keyboard::RightArrow),
+            &[
+                Keyboard::RightArrow,
+                Keyboard::LeftControl,
+                Keyboard::LeftShift,
+            ]
+        );
+    }
+
+    #[test]
+    fn leader_sequence_started_while_holding_fn() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                // Fn+Space
+                (0, 4, 10, true),
+                (50, 4, 5, true),
+                (100, 4, 10, false),
+                (150, 4, 5, false),
+                // G
+                (200, 2, 5, true),
+                (250, 2, 5, false),
+                // C
+                (300, 3, 3, true),
+                (350, 3, 3, false),
+            ],
+        );
+
+        // Only the macro copying the line is typed, as Fn was held rather than tapped. Every key in
+        // every report, so the modifiers show up for as long as they're held.
+        let typed: Vec<_> = reports.into_iter().flatten().collect();
+        assert_eq!(
+            typed,
+            [
+                Keyboard::Home,
+                Keyboard::LeftShift,
+                Keyboard::End,
+                Keyboard::LeftShift,
+                Keyboard::LeftShift,
+                Keyboard::LeftControl,
+                Keyboard::C,
+                Keyboard::LeftControl,
+                Keyboard::LeftControl,
+            ]
+        );
+    }
+
+    #[test]
+    fn fn_held_for_a_leader_key_is_not_left_armed() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 4, 10, true),
+                (50, 4, 5, true),
+                (100, 4, 10, false),
+                (150, 4, 5, false),
+                // S is PlayRecording(1) on the Fn layer
+                (200, 2, 2, true),
+                (250, 2, 2, false),
+            ],
+        );
+
+        let typed: Vec<_> = reports.into_iter().flatten().collect();
+        assert_eq!(typed, [Keyboard::S, Keyboard::LeftControl]);
+    }
+
+    #[test]
+    fn combo_keys_pressed_together_type_the_combo() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 7, true),
+                (10, 2, 8, true),
+                (100, 2, 7, false),
+                (110, 2, 8, false),
+            ],
+        );
+
+        assert_eq!(reports, [vec![Keyboard::Escape], vec![]]);
+    }
+
+    #[test]
+    fn combo_keys_pressed_too_far_apart_are_typed_as_themselves() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 7, true),
+                (60, 2, 8, true),
+                (90, 2, 7, false),
+                (120, 2, 8, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [vec![Keyboard::J], vec![], vec![Keyboard::K], vec![]]
+        );
+    }
+
+    #[test]
+    fn combo_keys_wait_for_the_biggest_combo_they_could_be_part_of() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 7, true),
+                (10, 2, 8, true),
+                (20, 2, 9, true),
+                (100, 2, 7, false),
+                (110, 2, 8, false),
+                (120, 2, 9, false),
+            ],
+        );
+
+        assert_eq!(reports, [vec![Keyboard::ReturnEnter], vec![]]);
+
+        // Only part of a combo, which is typed once it's let go
+        let mut engine = Engine::new();
+        let reports = run(&mut engine, &[(0, 2, 8, true), (30, 2, 8, false)]);
+
+        assert_eq!(reports, [vec![Keyboard::K], vec![]]);
+    }
+
+    #[test]
+    fn combo_is_released_with_the_first_of_its_keys() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[(0, 2, 7, true), (10, 2, 8, true), (100, 2, 7, false)],
+        );
+
+        assert_eq!(reports, [vec![Keyboard::Escape], vec![]]);
+
+        // The key still held was never pressed as far as the rest of the keymap knows
+        let time = Instant::from_ticks(2_000_000);
+        engine.process(KeyEvent {
+            row: 2,
+            col: 8,
+            pressed: false,
+            time,
+        });
+        engine.tick(time);
+
+        assert!(engine.next_report().is_none());
+    }
+
+    #[test]
+    fn keys_held_back_for_a_combo_are_typed_in_order_when_it_is_not() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 7, true),
+                (10, 3, 3, true),
+                (30, 2, 7, false),
+                (40, 3, 3, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::J],
+                vec![Keyboard::C, Keyboard::J],
+                vec![Keyboard::C],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_counts_taps() {
+        let mut engine = Engine::new();
+        let reports = run(&mut engine, &[(0, 2, 10, true), (30, 2, 10, false)]);
+
+        assert_eq!(reports, [vec![Keyboard::Semicolon], vec![]]);
+
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 10, true),
+                (30, 2, 10, false),
+                (80, 2, 10, true),
+                (110, 2, 10, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [vec![Keyboard::Semicolon, Keyboard::LeftShift], vec![]]
+        );
+    }
+
+    #[test]
+    fn tap_dance_taps_further_apart_than_the_tapping_term_start_again() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 10, true),
+                (30, 2, 10, false),
+                (300, 2, 10, true),
+                (330, 2, 10, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::Semicolon],
+                vec![],
+                vec![Keyboard::Semicolon],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_is_ended_by_another_key() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 10, true),
+                (30, 2, 10, false),
+                (80, 0, 1, true),
+                (90, 2, 10, true),
+                (120, 2, 10, false),
+                (200, 0, 1, false),
+            ],
+        );
+
+        // Two taps either side of the 1, rather than a double tap
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::Semicolon],
+                vec![],
+                vec![Keyboard::Keyboard1],
+                vec![Keyboard::Keyboard1, Keyboard::Semicolon],
+                vec![Keyboard::Keyboard1],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_held_on_its_last_tap() {
+        // Held on the first tap, which moves to the Fn layer
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 10, true),
+                (300, 0, 1, true),
+                (330, 0, 1, false),
+                (400, 2, 10, false),
+            ],
+        );
+
+        assert_eq!(reports, [vec![Keyboard::F1], vec![]]);
+
+        // Held on the second, which has no hold of its own and so holds down the :
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 2, 10, true),
+                (30, 2, 10, false),
+                (80, 2, 10, true),
+                (400, 0, 1, true),
+                (450, 0, 1, false),
+                (500, 2, 10, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::Semicolon, Keyboard::LeftShift],
+                vec![
+                    Keyboard::Keyboard1,
+                    Keyboard::Semicolon,
+                    Keyboard::LeftShift
+                ],
+                vec![Keyboard::Semicolon, Keyboard::LeftShift],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn grave_escape_types_grave_with_shift_or_gui_held() {
+        let mut engine = Engine::new();
+        let reports = run(&mut engine, &[(0, 0, 0, true), (30, 0, 0, false)]);
+
+        assert_eq!(reports, [vec![Keyboard::Escape], vec![]]);
+
+        // LSPO, then the left GUI, then the left Ctrl
+        for (row, col, modifier, key) in [
+            (3, 0, Keyboard::LeftShift, Keyboard::Grave),
+            (4, 1, Keyboard::LeftGUI, Keyboard::Grave),
+            (4, 0, Keyboard::LeftControl, Keyboard::Escape),
+        ] {
+            let mut engine = Engine::new();
+            let reports = run(
+                &mut engine,
+                &[
+                    (0, row, col, true),
+                    (100, 0, 0, true),
+                    (130, 0, 0, false),
+                    (200, row, col, false),
+                ],
+            );
+
+            assert_eq!(
+                reports,
+                [vec![modifier], vec![key, modifier], vec![modifier], vec![]]
+            );
+        }
+    }
+
+    #[test]
+    fn space_cadet_types_parentheses_when_tapped() {
+        for (col, shift, key) in [
+            (0, Keyboard::LeftShift, Keyboard::Keyboard9),
+            (12, Keyboard::RightShift, Keyboard::Keyboard0),
+        ] {
+            let mut engine = Engine::new();
+            let reports = run(&mut engine, &[(0, 3, col, true), (100, 3, col, false)]);
+
+            assert_eq!(
+                reports,
+                [vec![shift], vec![key, shift], vec![shift], vec![]]
+            );
+        }
+    }
+
+    #[test]
+    fn space_cadet_held_is_only_shift() {
+        // Held past the tapping term
+        let mut engine = Engine::new();
+        let reports = run(&mut engine, &[(0, 3, 0, true), (300, 3, 0, false)]);
+
+        assert_eq!(reports, [vec![Keyboard::LeftShift], vec![]]);
+
+        // Held while another key is tapped
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 3, 0, true),
+                (60, 3, 1, true),
+                (80, 3, 1, false),
+                (100, 3, 0, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::LeftShift],
+                vec![Keyboard::Z, Keyboard::LeftShift],
+                vec![Keyboard::LeftShift],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn space_cadet_rolled_into_another_key_is_only_shift() {
+        let mut engine = Engine::new();
+        let reports = run(
+            &mut engine,
+            &[
+                (0, 3, 0, true),
+                (60, 3, 1, true),
+                (80, 3, 0, false),
+                (100, 3, 1, false),
+            ],
+        );
+
+        assert_eq!(
+            reports,
+            [
+                vec![Keyboard::LeftShift],
+                vec![Keyboard::Z, Keyboard::LeftShift],
+                vec![Keyboard::Z],
+                vec![],
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::constants::AUTO_SHIFT_TIMEOUT;
+use crate::keymap::{Event, KeyId};
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use usbd_human_interface_device::page::Keyboard;
+
+/// Which keys send their shifted variant when held down a little longer than a tap
+#[derive(Copy, Clone)]
+pub struct AutoShiftConfig {
+    /// Held for at least this long, the key is shifted
+    pub timeout: MicrosDurationU32,
+    pub letters: bool,
+    pub numbers: bool,
+    pub symbols: bool,
+    /// Keep the shifted key held so the host repeats it, instead of sending it just once
+    pub repeat: bool,
+}
+
+impl AutoShiftConfig {
+    pub const DEFAULT: AutoShiftConfig = AutoShiftConfig {
+        timeout: AUTO_SHIFT_TIMEOUT,
+        letters: true,
+        numbers: true,
+        symbols: true,
+        repeat: false,
+    };
+
+    pub const fn without_numbers(self) -> Self {
+        AutoShiftConfig {
+            numbers: false,
+            ..self
+        }
+    }
+
+    pub fn applies_to(&self, key: Keyboard) -> bool {
+        let usage = u8::from(key);
+
+        let is_letter = (u8::from(Keyboard::A)..=u8::from(Keyboard::Z)).contains(&usage);
+        let is_number =
+            (u8::from(Keyboard::Keyboard1)..=u8::from(Keyboard::Keyboard0)).contains(&usage);
+        let is_symbol =
+            (u8::from(Keyboard::Minus)..=u8::from(Keyboard::ForwardSlash)).contains(&usage);
+
+        (self.letters && is_letter) || (self.numbers && is_number) || (self.symbols && is_symbol)
+    }
+}
+
+/// A key that could still be auto shifted, depending on how long it is held
+#[derive(Copy, Clone)]
+pub struct PendingAutoShift {
+    pub key: KeyId,
+    pub keycode: Keyboard,
+    pressed_at: Instant,
+}
+
+impl PendingAutoShift {
+    pub fn new(event: Event, keycode: Keyboard) -> Self {
+        PendingAutoShift {
+            key: event.key,
+            keycode,
+            pressed_at: event.time,
+        }
+    }
+
+    pub fn has_expired(&self, config: &AutoShiftConfig, now: Instant) -> bool {
+        now >= self.pressed_at + config.timeout
+    }
+
+    /// Decides whether the key is shifted given the next event, if that settles it
+    pub fn decide(&self, config: &AutoShiftConfig, event: &Event) -> Option<bool> {
+        if self.has_expired(config, event.time) {
+            Some(true)
+        } else if event.pressed || event.key == self.key {
+            // Typing on means the key was only being rolled over, not held on purpose
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::constants::CAPS_WORD_IDLE_TIMEOUT;
+use crate::report::Modifiers;
+use rp2040_hal::timer::Instant;
+use usbd_human_interface_device::page::Keyboard;
+
+/// Shifts letters until something that ends a word is typed, for writing out a single word in
+/// capitals without holding Shift or having to turn Caps Lock back off
+pub struct CapsWord {
+    active: bool,
+    // Whether the last key typed is one that gets shifted
+    shifted: bool,
+    last_press: Instant,
+}
+
+impl CapsWord {
+    pub const fn new() -> Self {
+        CapsWord {
+            active: false,
+            shifted: false,
+            last_press: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn toggle(&mut self, now: Instant) {
+        self.active = !self.active;
+        self.shifted = false;
+        self.last_press = now;
+    }
+
+    /// The modifiers to add to every report sent
+    pub fn modifiers(&self) -> Modifiers {
+        if self.active && self.shifted {
+            Modifiers::LEFT_SHIFT
+        } else {
+            Modifiers::NONE
+        }
+    }
+
+    /// Updates the word for a key about to be pressed, ending it if the key breaks the word
+    pub fn press(&mut self, key: Keyboard, now: Instant) {
+        if !self.active {
+            return;
+        }
+
+        self.last_press = now;
+
+        let usage = u8::from(key);
+        let is_letter = (u8::from(Keyboard::A)..=u8::from(Keyboard::Z)).contains(&usage);
+        let is_number =
+            (u8::from(Keyboard::Keyboard1)..=u8::from(Keyboard::Keyboard0)).contains(&usage);
+
+        // - becomes _ so names like MAX_LENGTH can be typed in one go
+        if is_letter || key == Keyboard::Minus {
+            self.shifted = true;
+        } else if is_number
+            || matches!(
+                key,
+                Keyboard::DeleteBackspace
+                    | Keyboard::DeleteForward
+                    | Keyboard::LeftShift
+                    | Keyboard::RightShift
+            )
+        {
+            self.shifted = false;
+        } else {
+            self.active = false;
+            self.shifted = false;
+        }
+    }
+
+    /// Ends the word if nothing has been typed for a while, returning whether the modifiers changed
+    pub fn expire(&mut self, now: Instant) -> bool {
+        if !self.active || now < self.last_press + CAPS_WORD_IDLE_TIMEOUT {
+            return false;
+        }
+
+        let was_shifted = self.shifted;
+        self.active = false;
+        self.shifted = false;
+        was_shifted
+    }
+}
@@ -0,0 +1,227 @@
+use crate::common::Queue;
+use crate::constants::{COMBO_BUFFER_SIZE, COMBO_TERM, MAX_COMBOS};
+use crate::keyboard::KeyEvent;
+use crate::keymap::{Action, Event, KeyId};
+use rp2040_hal::timer::Instant;
+
+/// Everything the combo stage can let through in response to one event
+pub type ComboOutput = Queue<Event, { COMBO_BUFFER_SIZE + 1 }>;
+
+/// A set of matrix positions that, pressed together, act as a key of their own
+#[derive(Copy, Clone)]
+pub struct Combo {
+    keys: [(u8, u8); COMBO_BUFFER_SIZE],
+    key_count: u8,
+    pub action: Action,
+}
+
+impl Combo {
+    /// A combo with no keys, which never fires, for the places in the keymap's table no combo is in
+    pub const NONE: Combo = Combo {
+        keys: [(0, 0); COMBO_BUFFER_SIZE],
+        key_count: 0,
+        action: Action::NoOp,
+    };
+
+    pub const fn new(keys: &[(u8, u8)], action: Action) -> Self {
+        assert!(
+            keys.len() > 1 && keys.len() <= COMBO_BUFFER_SIZE,
+            "Combos need between 2 and COMBO_BUFFER_SIZE keys"
+        );
+
+        let mut combo = Combo {
+            key_count: keys.len() as u8,
+            action,
+            ..Combo::NONE
+        };
+        let mut i = 0;
+        while i < keys.len() {
+            combo.keys[i] = keys[i];
+            i += 1;
+        }
+
+        combo
+    }
+
+    pub fn keys(&self) -> &[(u8, u8)] {
+        &self.keys[..self.key_count as usize]
+    }
+
+    fn contains(&self, row: u8, col: u8) -> bool {
+        self.keys().contains(&(row, col))
+    }
+
+    fn bit_of(&self, row: u8, col: u8) -> Option<u8> {
+        self.keys()
+            .iter()
+            .position(|&key| key == (row, col))
+            .map(|i| 1 << i)
+    }
+
+    /// Whether every pressed key could still be part of this combo
+    fn accepts<'a>(&self, mut pressed: impl Iterator<Item = &'a KeyEvent>) -> bool {
+        pressed.all(|event| self.contains(event.row, event.col))
+    }
+
+    /// Whether every key of this combo has been pressed
+    fn is_completed_by(&self, pressed: &Queue<KeyEvent, COMBO_BUFFER_SIZE>) -> bool {
+        self.key_count > 0
+            && self
+                .keys()
+                .iter()
+                .all(|&key| pressed.iter().any(|event| (event.row, event.col) == key))
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ActiveCombo {
+    // Bits of the combo's keys that haven't been released yet
+    held_keys: u8,
+    // Combos are released as soon as any of their keys is
+    released: bool,
+}
+
+/// Holds back key presses that might be the start of a combo until it is clear whether they are
+pub struct ComboState {
+    pressed: Queue<KeyEvent, COMBO_BUFFER_SIZE>,
+    active: [Option<ActiveCombo>; MAX_COMBOS],
+}
+
+impl ComboState {
+    pub const fn new() -> Self {
+        ComboState {
+            pressed: Queue::new(),
+            active: [None; MAX_COMBOS],
+        }
+    }
+
+    pub fn process(&mut self, combos: &[Combo], event: KeyEvent, output: &mut ComboOutput) {
+        if !event.pressed {
+            return self.process_release(combos, event, output);
+        }
+
+        let is_candidate = combos.iter().any(|combo| {
+            combo.contains(event.row, event.col) && combo.accepts(self.pressed.iter())
+        });
+
+        if !is_candidate {
+            // The held back keys can't become a combo anymore, but this key might start a new one
+            if !self.pressed.is_empty() {
+                self.resolve(combos, output);
+                return self.process(combos, event, output);
+            }
+
+            let _ = output.push(Event::from(event));
+            return;
+        }
+
+        if let Err(event) = self.pressed.push(event) {
+            self.resolve(combos, output);
+            return self.process(combos, event, output);
+        }
+
+        // Fire straight away unless pressing more keys could still complete a bigger combo
+        let is_exact = combos.iter().any(|combo| {
+            combo.accepts(self.pressed.iter()) && combo.is_completed_by(&self.pressed)
+        });
+        let could_grow = combos.iter().any(|combo| {
+            combo.accepts(self.pressed.iter()) && !combo.is_completed_by(&self.pressed)
+        });
+
+        if is_exact && !could_grow {
+            self.resolve(combos, output);
+        }
+    }
+
+    pub fn tick(&mut self, combos: &[Combo], now: Instant, output: &mut ComboOutput) {
+        if self
+            .pressed
+            .iter()
+            .next()
+            .is_some_and(|first| now >= first.time + COMBO_TERM)
+        {
+            self.resolve(combos, output);
+        }
+    }
+
+    fn process_release(&mut self, combos: &[Combo], event: KeyEvent, output: &mut ComboOutput) {
+        if self
+            .pressed
+            .iter()
+            .any(|pressed| pressed.row == event.row && pressed.col == event.col)
+        {
+            // Letting go of a held back key settles things before it is passed on
+            self.resolve(combos, output);
+        }
+
+        for (index, combo) in combos.iter().enumerate() {
+            let (Some(active), Some(bit)) =
+                (&mut self.active[index], combo.bit_of(event.row, event.col))
+            else {
+                continue;
+            };
+
+            if active.held_keys & bit == 0 {
+                continue;
+            }
+
+            active.held_keys &= !bit;
+
+            if !active.released {
+                active.released = true;
+                let _ = output.push(Event {
+                    key: KeyId::Combo(index as u8),
+                    pressed: false,
+                    time: event.time,
+                });
+            }
+
+            if active.held_keys == 0 {
+                self.active[index] = None;
+            }
+
+            // The key belonged to a combo, so the rest of the keymap never saw it pressed
+            return;
+        }
+
+        let _ = output.push(Event::from(event));
+    }
+
+    /// Fires the biggest combo the held back keys complete, and lets any other keys through as normal
+    fn resolve(&mut self, combos: &[Combo], output: &mut ComboOutput) {
+        let fired = combos
+            .iter()
+            .enumerate()
+            .filter(|(index, combo)| {
+                self.active[*index].is_none() && combo.is_completed_by(&self.pressed)
+            })
+            .max_by_key(|(_, combo)| combo.key_count);
+
+        if let Some((index, combo)) = fired {
+            let time = self
+                .pressed
+                .iter()
+                .filter(|event| combo.contains(event.row, event.col))
+                .map(|event| event.time)
+                .max()
+                .unwrap_or_else(|| Instant::from_ticks(0));
+
+            self.active[index] = Some(ActiveCombo {
+                held_keys: u8::MAX >> (u8::BITS - combo.key_count as u32),
+                released: false,
+            });
+
+            let _ = output.push(Event {
+                key: KeyId::Combo(index as u8),
+                pressed: true,
+                time,
+            });
+        }
+
+        while let Some(event) = self.pressed.pop() {
+            if !fired.is_some_and(|(_, combo)| combo.contains(event.row, event.col)) {
+                let _ = output.push(Event::from(event));
+            }
+        }
+    }
+}
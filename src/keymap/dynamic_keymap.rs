@@ -0,0 +1,45 @@
+use crate::constants::DYNAMIC_KEYMAP_LAYERS;
+use crate::keymap::Action;
+
+/// Keys changed while the keyboard is running, laid over the actions the keymap was built with
+pub struct DynamicKeymap<const NROW: usize, const NCOL: usize> {
+    // Nothing where a key is still the keymap's own
+    changed: [[[Option<Action>; NCOL]; NROW]; DYNAMIC_KEYMAP_LAYERS],
+}
+
+impl<const NROW: usize, const NCOL: usize> DynamicKeymap<NROW, NCOL> {
+    pub const fn new() -> Self {
+        DynamicKeymap {
+            changed: [[[None; NCOL]; NROW]; DYNAMIC_KEYMAP_LAYERS],
+        }
+    }
+
+    /// What a key was changed to, if it has been
+    pub fn get(&self, layer: usize, row: u8, col: u8) -> Option<Action> {
+        *self
+            .changed
+            .get(layer)?
+            .get(row as usize)?
+            .get(col as usize)?
+    }
+
+    /// Changes a key, returning whether it is somewhere that can be changed
+    pub fn set(&mut self, layer: usize, row: u8, col: u8, action: Action) -> bool {
+        let Some(key) = self
+            .changed
+            .get_mut(layer)
+            .and_then(|rows| rows.get_mut(row as usize))
+            .and_then(|cols| cols.get_mut(col as usize))
+        else {
+            return false;
+        };
+
+        *key = Some(action);
+        true
+    }
+
+    /// Puts every key back to what the keymap was built with
+    pub fn reset(&mut self) {
+        self.changed = [[[None; NCOL]; NROW]; DYNAMIC_KEYMAP_LAYERS];
+    }
+}
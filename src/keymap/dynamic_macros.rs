@@ -0,0 +1,101 @@
+use crate::constants::{DYNAMIC_MACRO_LENGTH, DYNAMIC_MACRO_SLOTS};
+use crate::keymap::MacroStep;
+use crate::report::KeyboardReport;
+use usbd_human_interface_device::page::Keyboard;
+
+#[derive(Copy, Clone)]
+struct Recording {
+    steps: [MacroStep; DYNAMIC_MACRO_LENGTH],
+    len: usize,
+}
+
+#[derive(Copy, Clone)]
+struct ActiveRecording {
+    slot: u8,
+    // What the keyboard was sending when the last step was recorded
+    last: KeyboardReport,
+}
+
+/// Macros recorded on the keyboard itself, kept in RAM
+pub struct DynamicMacros {
+    recordings: [Recording; DYNAMIC_MACRO_SLOTS],
+    recording: Option<ActiveRecording>,
+}
+
+impl DynamicMacros {
+    pub const fn new() -> Self {
+        DynamicMacros {
+            recordings: [Recording {
+                steps: [MacroStep::Release(Keyboard::NoEventIndicated); DYNAMIC_MACRO_LENGTH],
+                len: 0,
+            }; DYNAMIC_MACRO_SLOTS],
+            recording: None,
+        }
+    }
+
+    /// Starts recording over a slot, from whatever the keyboard is currently sending
+    pub fn start(&mut self, slot: u8, current: &KeyboardReport) {
+        if let Some(recording) = self.recordings.get_mut(slot as usize) {
+            recording.len = 0;
+            self.recording = Some(ActiveRecording {
+                slot,
+                last: *current,
+            });
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn is_recording_into(&self, slot: u8) -> bool {
+        self.recording
+            .is_some_and(|recording| recording.slot == slot)
+    }
+
+    /// Records the keys that changed since the last report as presses and releases
+    pub fn record(&mut self, report: &KeyboardReport) {
+        let Some(active) = &mut self.recording else {
+            return;
+        };
+
+        let recording = &mut self.recordings[active.slot as usize];
+        let released = active
+            .last
+            .keys()
+            .filter(|&key| !report.is_pressed(key))
+            .map(MacroStep::Release);
+        let pressed = report
+            .keys()
+            .filter(|&key| !active.last.is_pressed(key))
+            .map(MacroStep::Press);
+
+        let mut is_full = false;
+        for step in released.chain(pressed) {
+            let Some(slot) = recording.steps.get_mut(recording.len) else {
+                is_full = true;
+                break;
+            };
+
+            *slot = step;
+            recording.len += 1;
+        }
+
+        active.last = *report;
+
+        if is_full {
+            // Out of space, keep what fit
+            self.recording = None;
+        }
+    }
+
+    pub fn steps(&self, slot: u8) -> &[MacroStep] {
+        self.recordings
+            .get(slot as usize)
+            .map_or(&[], |recording| &recording.steps[..recording.len])
+    }
+}
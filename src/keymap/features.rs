@@ -0,0 +1,46 @@
+use crate::constants::{MAX_COMBOS, MAX_KEY_OVERRIDES, MAX_TAP_DANCES};
+use crate::keymap::{Combo, KeyOverride, TapDance};
+
+/// The combos, tap dances and key overrides in use, copied out of the keymap so they can be edited
+/// from Vial while the keyboard is running. Places the keymap leaves empty hold ones that do
+/// nothing.
+pub struct Features {
+    pub combos: [Combo; MAX_COMBOS],
+    pub tap_dances: [TapDance; MAX_TAP_DANCES],
+    pub key_overrides: [KeyOverride; MAX_KEY_OVERRIDES],
+}
+
+impl Features {
+    pub const fn new(
+        combos: &[Combo],
+        tap_dances: &[TapDance],
+        key_overrides: &[KeyOverride],
+    ) -> Self {
+        let mut features = Features {
+            combos: [Combo::NONE; MAX_COMBOS],
+            tap_dances: [TapDance::NONE; MAX_TAP_DANCES],
+            key_overrides: [KeyOverride::NONE; MAX_KEY_OVERRIDES],
+        };
+
+        // Loops, as slices can't be copied from in const fns
+        let mut i = 0;
+        while i < combos.len() {
+            features.combos[i] = combos[i];
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < tap_dances.len() {
+            features.tap_dances[i] = tap_dances[i];
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < key_overrides.len() {
+            features.key_overrides[i] = key_overrides[i];
+            i += 1;
+        }
+
+        features
+    }
+}
@@ -0,0 +1,88 @@
+use crate::keymap::{Action, KeyId};
+use crate::report::Modifiers;
+use usbd_human_interface_device::page::Keyboard;
+
+/// A key that does something else while certain modifiers are held, like Shift+Backspace for
+/// Delete. The modifiers that trigger it are hidden from the host while it is held.
+#[derive(Copy, Clone)]
+pub struct KeyOverride {
+    /// Holding any of these triggers the override
+    modifiers: Modifiers,
+    key: Keyboard,
+    replacement: Action,
+}
+
+impl KeyOverride {
+    /// An override nothing triggers, for the places in the keymap's table no override is in
+    pub const NONE: KeyOverride = KeyOverride {
+        modifiers: Modifiers::NONE,
+        key: Keyboard::NoEventIndicated,
+        replacement: Action::NoOp,
+    };
+
+    pub const fn new(modifiers: Modifiers, key: Keyboard, replacement: Action) -> Self {
+        assert!(
+            !modifiers.is_empty(),
+            "Key overrides need a modifier to trigger them"
+        );
+
+        KeyOverride {
+            modifiers,
+            key,
+            replacement,
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn key(&self) -> Keyboard {
+        self.key
+    }
+
+    pub fn replacement(&self) -> Action {
+        self.replacement
+    }
+
+    /// The modifiers to hide from the host if this overrides a key pressed with some held
+    fn triggered_by(&self, key: Keyboard, held: Modifiers) -> Option<Modifiers> {
+        let triggering = held.intersection(self.modifiers);
+        if key != self.key || triggering.is_empty() {
+            return None;
+        }
+
+        // The replacement may want some of the same modifiers, like Shift for ~
+        match self.replacement {
+            Action::ModifiedKey(modifiers, _) | Action::Modifiers(modifiers) => {
+                Some(triggering.difference(modifiers))
+            }
+            _ => Some(triggering),
+        }
+    }
+}
+
+/// The override currently replacing a held key, only one of which can be active at a time
+#[derive(Copy, Clone)]
+pub struct ActiveOverride {
+    pub key: KeyId,
+    pub suppressed: Modifiers,
+}
+
+/// Finds the first override for a key pressed with some modifiers held, and what it replaces the
+/// key's action with
+pub fn find(
+    overrides: &[KeyOverride],
+    key: KeyId,
+    action: Action,
+    held: Modifiers,
+) -> Option<(ActiveOverride, Action)> {
+    let Action::Key(keycode) = action else {
+        return None;
+    };
+
+    overrides.iter().find_map(|key_override| {
+        let suppressed = key_override.triggered_by(keycode, held)?;
+        Some((ActiveOverride { key, suppressed }, key_override.replacement))
+    })
+}
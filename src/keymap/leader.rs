@@ -0,0 +1,212 @@
+use crate::constants::{LEADER_MAX_LENGTH, LEADER_TIMEOUT};
+use crate::keymap::Action;
+use rp2040_hal::timer::Instant;
+use usbd_human_interface_device::page::Keyboard;
+
+/// Keys typed one after the other following the leader key, standing in for an action
+pub struct LeaderSequence {
+    keys: &'static [Keyboard],
+    action: Action,
+}
+
+impl LeaderSequence {
+    pub const fn new(keys: &'static [Keyboard], action: Action) -> Self {
+        assert!(
+            !keys.is_empty() && keys.len() <= LEADER_MAX_LENGTH,
+            "Leader sequences need between 1 and LEADER_MAX_LENGTH keys"
+        );
+
+        LeaderSequence { keys, action }
+    }
+}
+
+/// The key a pressed action counts as in a leader sequence, if it can be part of one
+pub fn sequence_key(action: Action) -> Option<Keyboard> {
+    match action {
+        Action::Key(key) | Action::ModTap(_, key) | Action::LayerTap(_, key) => Some(key),
+        _ => None,
+    }
+}
+
+pub enum LeaderOutcome {
+    /// The keys so far could still become a longer sequence
+    Waiting,
+    Matched(Action),
+    /// No sequence starts with the keys so far
+    Failed,
+}
+
+/// Collects the keys typed after the leader key until they match a sequence or time runs out
+pub struct LeaderState {
+    active: bool,
+    keys: [Keyboard; LEADER_MAX_LENGTH],
+    len: usize,
+    last_press: Instant,
+}
+
+impl LeaderState {
+    pub const fn new() -> Self {
+        LeaderState {
+            active: false,
+            keys: [Keyboard::NoEventIndicated; LEADER_MAX_LENGTH],
+            len: 0,
+            last_press: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        self.active = true;
+        self.len = 0;
+        self.last_press = now;
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = false;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn push(
+        &mut self,
+        sequences: &[LeaderSequence],
+        key: Keyboard,
+        now: Instant,
+    ) -> LeaderOutcome {
+        self.keys[self.len] = key;
+        self.len += 1;
+        self.last_press = now;
+
+        let typed = &self.keys[..self.len];
+        let exact = sequences.iter().find(|sequence| sequence.keys == typed);
+        let could_grow = sequences
+            .iter()
+            .any(|sequence| sequence.keys.len() > typed.len() && sequence.keys.starts_with(typed));
+
+        match (exact, could_grow) {
+            (Some(sequence), false) => {
+                self.active = false;
+                LeaderOutcome::Matched(sequence.action)
+            }
+            (_, true) => LeaderOutcome::Waiting,
+            _ => {
+                self.active = false;
+                LeaderOutcome::Failed
+            }
+        }
+    }
+
+    /// Ends a sequence that timed out, returning the action of the keys typed so far if any.
+    /// Every key typed gets a fresh timeout, so longer sequences don't need to be rushed.
+    pub fn expire(&mut self, sequences: &[LeaderSequence], now: Instant) -> Option<Action> {
+        if !self.active || now < self.last_press + LEADER_TIMEOUT {
+            return None;
+        }
+        self.active = false;
+
+        let typed = &self.keys[..self.len];
+        sequences
+            .iter()
+            .find(|sequence| sequence.keys == typed)
+            .map(|sequence| sequence.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCES: &[LeaderSequence] = &[
+        LeaderSequence::new(&[Keyboard::S], Action::Key(Keyboard::F1)),
+        LeaderSequence::new(&[Keyboard::G], Action::Key(Keyboard::F2)),
+        LeaderSequence::new(&[Keyboard::G, Keyboard::C], Action::Key(Keyboard::F3)),
+        LeaderSequence::new(
+            &[Keyboard::G, Keyboard::C, Keyboard::C],
+            Action::Key(Keyboard::F4),
+        ),
+        LeaderSequence::new(&[Keyboard::U, Keyboard::L], Action::Key(Keyboard::F5)),
+    ];
+
+    fn millis(millis: u64) -> Instant {
+        Instant::from_ticks(millis * 1000)
+    }
+
+    fn started() -> LeaderState {
+        let mut leader = LeaderState::new();
+        leader.start(millis(0));
+        leader
+    }
+
+    #[test]
+    fn sequences_match_once_they_cannot_grow() {
+        let mut leader = started();
+        let outcome = leader.push(SEQUENCES, Keyboard::S, millis(10));
+
+        assert!(matches!(
+            outcome,
+            LeaderOutcome::Matched(Action::Key(Keyboard::F1))
+        ));
+        assert!(!leader.is_active());
+
+        let mut leader = started();
+        for key in [Keyboard::G, Keyboard::C] {
+            let outcome = leader.push(SEQUENCES, key, millis(10));
+            assert!(matches!(outcome, LeaderOutcome::Waiting));
+        }
+        let outcome = leader.push(SEQUENCES, Keyboard::C, millis(20));
+
+        assert!(matches!(
+            outcome,
+            LeaderOutcome::Matched(Action::Key(Keyboard::F4))
+        ));
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn sequences_that_could_grow_match_when_they_time_out() {
+        let mut leader = started();
+        leader.push(SEQUENCES, Keyboard::G, millis(100));
+        leader.push(SEQUENCES, Keyboard::C, millis(400));
+
+        // Each key starts the timeout again
+        let timeout = LEADER_TIMEOUT.to_millis() as u64;
+        assert_eq!(leader.expire(SEQUENCES, millis(400 + timeout - 1)), None);
+        assert!(leader.is_active());
+        assert_eq!(
+            leader.expire(SEQUENCES, millis(400 + timeout)),
+            Some(Action::Key(Keyboard::F3))
+        );
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn sequences_that_time_out_part_way_do_nothing() {
+        let mut leader = started();
+        leader.push(SEQUENCES, Keyboard::U, millis(100));
+
+        assert_eq!(leader.expire(SEQUENCES, millis(1000)), None);
+        assert!(!leader.is_active());
+
+        // Nothing typed at all
+        let mut leader = started();
+        assert_eq!(leader.expire(SEQUENCES, millis(1000)), None);
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn keys_no_sequence_starts_with_end_it() {
+        let mut leader = started();
+        let outcome = leader.push(SEQUENCES, Keyboard::X, millis(10));
+
+        assert!(matches!(outcome, LeaderOutcome::Failed));
+        assert!(!leader.is_active());
+
+        let mut leader = started();
+        leader.push(SEQUENCES, Keyboard::G, millis(10));
+        let outcome = leader.push(SEQUENCES, Keyboard::X, millis(20));
+
+        assert!(matches!(outcome, LeaderOutcome::Failed));
+        assert!(!leader.is_active());
+    }
+}
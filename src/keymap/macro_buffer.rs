@@ -0,0 +1,217 @@
+use crate::constants::VIA_MACRO_BUFFER_SIZE;
+use crate::keymap::MacroStep;
+use crate::report::Modifiers;
+use rp2040_hal::fugit::MicrosDurationU32;
+use usbd_human_interface_device::page::Keyboard;
+
+// How VIA marks the actions in a macro, as opposed to the text typed out
+const ACTION_PREFIX: u8 = 0x01;
+const TAP: u8 = 0x01;
+const PRESS: u8 = 0x02;
+const RELEASE: u8 = 0x03;
+/// Followed by the milliseconds in decimal digits, ended by a `|`
+const DELAY: u8 = 0x04;
+
+/// Macros edited from VIA, stored one after the other each ended by a zero byte, in the format
+/// VIA reads and writes them
+pub struct MacroBuffer {
+    bytes: [u8; VIA_MACRO_BUFFER_SIZE],
+}
+
+impl MacroBuffer {
+    pub const fn new() -> Self {
+        MacroBuffer {
+            bytes: [0; VIA_MACRO_BUFFER_SIZE],
+        }
+    }
+
+    /// Copies out as much of the buffer from an offset as fits, leaving the rest of `data` alone
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        let bytes = self.bytes.get(offset..).unwrap_or_default();
+        let length = data.len().min(bytes.len());
+        data[..length].copy_from_slice(&bytes[..length]);
+    }
+
+    /// Writes into the buffer from an offset, dropping anything past its end
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        let bytes = self.bytes.get_mut(offset..).unwrap_or_default();
+        let length = data.len().min(bytes.len());
+        bytes[..length].copy_from_slice(&data[..length]);
+    }
+
+    pub fn reset(&mut self) {
+        self.bytes = [0; VIA_MACRO_BUFFER_SIZE];
+    }
+
+    /// The step at an index of one of the macros, or nothing once it has all been played
+    pub fn step(&self, index: u8, step: usize) -> Option<MacroStep> {
+        let mut bytes = self.bytes.split(|&byte| byte == 0).nth(index as usize)?;
+        let mut remaining = step;
+
+        // Worked out from the start each time, like Unicode characters, so nothing is stored
+        loop {
+            let (length, steps) = parse(bytes)?;
+            bytes = &bytes[length..];
+
+            for step in steps.into_iter().flatten() {
+                if remaining == 0 {
+                    return Some(step);
+                }
+                remaining -= 1;
+            }
+        }
+    }
+}
+
+/// The steps for the action or character at the start of a macro, and how many bytes it takes up
+fn parse(bytes: &[u8]) -> Option<(usize, [Option<MacroStep>; 3])> {
+    match *bytes {
+        [] => None,
+        [ACTION_PREFIX, code @ (TAP | PRESS | RELEASE), keycode, ..] => {
+            // Only the basic keycodes, which are the same as their usages
+            let key = Keyboard::from(keycode);
+            let step = match code {
+                TAP => MacroStep::Tap(key),
+                PRESS => MacroStep::Press(key),
+                _ => MacroStep::Release(key),
+            };
+
+            Some((3, [Some(step), None, None]))
+        }
+        [ACTION_PREFIX, DELAY, ref rest @ ..] => {
+            let digits = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+            let millis = rest[..digits].iter().fold(0u32, |millis, digit| {
+                millis
+                    .saturating_mul(10)
+                    .saturating_add((digit - b'0') as u32)
+            });
+            let length = 2 + (digits + 1).min(rest.len());
+
+            // Saturated again in microseconds, as a delay sent by the host can be any length
+            let delay =
+                MacroStep::Delay(MicrosDurationU32::from_ticks(millis.saturating_mul(1000)));
+            Some((length, [Some(delay), None, None]))
+        }
+        // Newer actions with 16 bit keycodes, which can't be played here
+        [ACTION_PREFIX, ..] => None,
+        [character, ..] => {
+            let steps = match typed_with(character) {
+                Some((key, false)) => [Some(MacroStep::Tap(key)), None, None],
+                Some((key, true)) => [
+                    Some(MacroStep::PressModifiers(Modifiers::LEFT_SHIFT)),
+                    Some(MacroStep::Tap(key)),
+                    Some(MacroStep::ReleaseModifiers(Modifiers::LEFT_SHIFT)),
+                ],
+                // Characters there's no key for are skipped
+                None => [None; 3],
+            };
+
+            Some((1, steps))
+        }
+    }
+}
+
+/// The key that types an ASCII character on a US layout, and whether it needs Shift
+fn typed_with(character: u8) -> Option<(Keyboard, bool)> {
+    let offset = |first: Keyboard, from: u8| Keyboard::from(u8::from(first) + character - from);
+
+    let typed = match character {
+        b'a'..=b'z' => (offset(Keyboard::A, b'a'), false),
+        b'A'..=b'Z' => (offset(Keyboard::A, b'A'), true),
+        b'1'..=b'9' => (offset(Keyboard::Keyboard1, b'1'), false),
+        b'0' => (Keyboard::Keyboard0, false),
+        b'\n' => (Keyboard::ReturnEnter, false),
+        b'\t' => (Keyboard::Tab, false),
+        b' ' => (Keyboard::Space, false),
+        b'!' => (Keyboard::Keyboard1, true),
+        b'@' => (Keyboard::Keyboard2, true),
+        b'#' => (Keyboard::Keyboard3, true),
+        b'$' => (Keyboard::Keyboard4, true),
+        b'%' => (Keyboard::Keyboard5, true),
+        b'^' => (Keyboard::Keyboard6, true),
+        b'&' => (Keyboard::Keyboard7, true),
+        b'*' => (Keyboard::Keyboard8, true),
+        b'(' => (Keyboard::Keyboard9, true),
+        b')' => (Keyboard::Keyboard0, true),
+        b'-' => (Keyboard::Minus, false),
+        b'_' => (Keyboard::Minus, true),
+        b'=' => (Keyboard::Equal, false),
+        b'+' => (Keyboard::Equal, true),
+        b'[' => (Keyboard::LeftBrace, false),
+        b'{' => (Keyboard::LeftBrace, true),
+        b']' => (Keyboard::RightBrace, false),
+        b'}' => (Keyboard::RightBrace, true),
+        b'\\' => (Keyboard::Backslash, false),
+        b'|' => (Keyboard::Backslash, true),
+        b';' => (Keyboard::Semicolon, false),
+        b':' => (Keyboard::Semicolon, true),
+        b'\'' => (Keyboard::Apostrophe, false),
+        b'"' => (Keyboard::Apostrophe, true),
+        b'`' => (Keyboard::Grave, false),
+        b'~' => (Keyboard::Grave, true),
+        b',' => (Keyboard::Comma, false),
+        b'<' => (Keyboard::Comma, true),
+        b'.' => (Keyboard::Dot, false),
+        b'>' => (Keyboard::Dot, true),
+        b'/' => (Keyboard::ForwardSlash, false),
+        b'?' => (Keyboard::ForwardSlash, true),
+        _ => return None,
+    };
+
+    Some(typed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_are_read_in_milliseconds() {
+        let (length, steps) = parse(b"\x01\x04250|a").unwrap();
+
+        assert_eq!(length, 6);
+        assert_eq!(
+            steps,
+            [
+                Some(MacroStep::Delay(MicrosDurationU32::millis(250))),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn delays_too_long_to_count_in_microseconds_wait_as_long_as_they_can() {
+        let (length, steps) = parse(b"\x01\x049999999999|").unwrap();
+
+        assert_eq!(length, 13);
+        assert_eq!(
+            steps,
+            [
+                Some(MacroStep::Delay(MicrosDurationU32::from_ticks(u32::MAX))),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_are_played_from_the_buffer() {
+        let mut buffer = MacroBuffer::new();
+        buffer.write(0, b"x\0\x01\x01\x29A");
+
+        assert_eq!(buffer.step(0, 0), Some(MacroStep::Tap(Keyboard::X)));
+        assert_eq!(buffer.step(0, 1), None);
+        assert_eq!(buffer.step(1, 0), Some(MacroStep::Tap(Keyboard::Escape)));
+        assert_eq!(
+            buffer.step(1, 1),
+            Some(MacroStep::PressModifiers(Modifiers::LEFT_SHIFT))
+        );
+        assert_eq!(buffer.step(1, 2), Some(MacroStep::Tap(Keyboard::A)));
+        assert_eq!(
+            buffer.step(1, 3),
+            Some(MacroStep::ReleaseModifiers(Modifiers::LEFT_SHIFT))
+        );
+        assert_eq!(buffer.step(1, 4), None);
+    }
+}
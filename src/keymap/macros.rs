@@ -0,0 +1,182 @@
+use crate::common::Queue;
+use crate::constants::MACRO_QUEUE_SIZE;
+use crate::keymap::dynamic_macros::DynamicMacros;
+use crate::keymap::macro_buffer::MacroBuffer;
+use crate::keymap::unicode::{self, UnicodeMode};
+use crate::report::{KeyboardReport, Modifiers};
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use usbd_human_interface_device::page::Keyboard;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MacroStep {
+    Press(Keyboard),
+    Release(Keyboard),
+    /// A press and a release, sent as two separate reports
+    Tap(Keyboard),
+    PressModifiers(Modifiers),
+    ReleaseModifiers(Modifiers),
+    Delay(MicrosDurationU32),
+}
+
+/// A sequence of key presses played out one report at a time
+pub struct Macro {
+    steps: &'static [MacroStep],
+    keep_modifiers: bool,
+}
+
+impl Macro {
+    pub const fn new(steps: &'static [MacroStep]) -> Self {
+        Macro {
+            steps,
+            keep_modifiers: false,
+        }
+    }
+
+    /// Lets modifiers held on the keyboard apply to the macro, instead of hiding them while it plays
+    pub const fn keep_modifiers(self) -> Self {
+        Macro {
+            keep_modifiers: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MacroSource {
+    /// One of the keymap's macros
+    Static(u8),
+    /// One of the macros recorded on the keyboard
+    Recorded(u8),
+    /// A code point typed in the way the host expects
+    Unicode(char, UnicodeMode),
+    /// One of the macros edited from VIA
+    Buffer(u8),
+}
+
+#[derive(Copy, Clone)]
+struct Playback {
+    source: MacroSource,
+    step: usize,
+    // Set while a tap is waiting for its release to be sent
+    tapped: Option<Keyboard>,
+    resume_at: Option<Instant>,
+}
+
+/// Plays macros back over successive reports, keeping the keys they press apart from the real ones
+pub struct MacroPlayer {
+    queued: Queue<MacroSource, MACRO_QUEUE_SIZE>,
+    playing: Option<Playback>,
+    report: KeyboardReport,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        MacroPlayer {
+            queued: Queue::new(),
+            playing: None,
+            report: KeyboardReport::new(),
+        }
+    }
+
+    pub fn play(&mut self, source: MacroSource) {
+        // A macro triggered while the queue is full is dropped rather than half played later
+        let _ = self.queued.push(source);
+    }
+
+    /// The keys pressed by the macro currently playing
+    pub fn report(&self) -> &KeyboardReport {
+        &self.report
+    }
+
+    /// Whether modifiers held on the keyboard should be hidden from the host right now
+    pub fn masks_modifiers(&self, macros: &[Macro]) -> bool {
+        self.playing.is_some_and(|playback| match playback.source {
+            MacroSource::Static(index) => !macros[index as usize].keep_modifiers,
+            // Recordings already contain whatever modifiers were held while recording
+            MacroSource::Recorded(_) => true,
+            // A held Shift would change the hex digits typed
+            MacroSource::Unicode(..) => true,
+            // Typed out as text, which held modifiers would turn into shortcuts
+            MacroSource::Buffer(_) => true,
+        })
+    }
+
+    /// Runs steps until the macro's keys change, returning whether they did
+    pub fn advance(
+        &mut self,
+        macros: &[Macro],
+        recordings: &DynamicMacros,
+        buffer: &MacroBuffer,
+        now: Instant,
+    ) -> bool {
+        loop {
+            let Some(playback) = &mut self.playing else {
+                let Some(source) = self.queued.pop() else {
+                    return false;
+                };
+
+                self.playing = Some(Playback {
+                    source,
+                    step: 0,
+                    tapped: None,
+                    resume_at: None,
+                });
+                continue;
+            };
+
+            if let Some(key) = playback.tapped.take() {
+                self.report.release(key);
+                return true;
+            }
+
+            if let Some(resume_at) = playback.resume_at {
+                if now < resume_at {
+                    return false;
+                }
+
+                playback.resume_at = None;
+            }
+
+            let step = match playback.source {
+                MacroSource::Static(index) => {
+                    macros[index as usize].steps.get(playback.step).copied()
+                }
+                MacroSource::Recorded(slot) => recordings.steps(slot).get(playback.step).copied(),
+                MacroSource::Unicode(character, mode) => {
+                    unicode::step(character, mode, playback.step)
+                }
+                MacroSource::Buffer(index) => buffer.step(index, playback.step),
+            };
+
+            let Some(step) = step else {
+                // Don't leave anything the macro forgot to release held down
+                self.playing = None;
+                if self.report != KeyboardReport::new() {
+                    self.report = KeyboardReport::new();
+                    return true;
+                }
+
+                continue;
+            };
+            playback.step += 1;
+
+            match step {
+                MacroStep::Press(key) => self.report.press(key),
+                MacroStep::Release(key) => self.report.release(key),
+                MacroStep::Tap(key) => {
+                    playback.tapped = Some(key);
+                    self.report.press(key);
+                }
+                MacroStep::PressModifiers(modifiers) => self.report.press_modifiers(modifiers),
+                MacroStep::ReleaseModifiers(modifiers) => self.report.release_modifiers(modifiers),
+                MacroStep::Delay(delay) => {
+                    playback.resume_at = Some(now + delay);
+                    continue;
+                }
+            }
+
+            return true;
+        }
+    }
+}
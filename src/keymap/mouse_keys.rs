@@ -0,0 +1,184 @@
+use crate::constants::{MOUSE_MOVE_INTERVAL, MOUSE_WHEEL_INTERVAL};
+use crate::report::MouseReport;
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MouseDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// How the cursor speeds up while a movement key is held
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccelerationCurve {
+    Linear,
+    /// Slow for longer at the start, for finer control over short movements
+    Quadratic,
+}
+
+#[derive(Copy, Clone)]
+pub struct MouseKeysConfig {
+    /// Pixels moved per movement report when a key is first pressed
+    pub min_speed: u8,
+    /// Pixels moved per movement report once fully accelerated, no less than `min_speed` and no
+    /// more than 127 to fit in a report
+    pub max_speed: u8,
+    /// How long a movement key has to be held to reach the top speed
+    pub time_to_max: MicrosDurationU32,
+    pub curve: AccelerationCurve,
+    /// Wheel clicks per wheel report
+    pub wheel_speed: u8,
+}
+
+impl MouseKeysConfig {
+    pub const DEFAULT: MouseKeysConfig = MouseKeysConfig {
+        min_speed: 1,
+        max_speed: 20,
+        time_to_max: MicrosDurationU32::millis(1000),
+        curve: AccelerationCurve::Linear,
+        wheel_speed: 1,
+    };
+
+    pub const fn curve(self, curve: AccelerationCurve) -> Self {
+        MouseKeysConfig { curve, ..self }
+    }
+
+    /// The pixels to move per report after moving for some time
+    fn speed_after(&self, moving_for: u64) -> i8 {
+        let full = self.time_to_max.to_micros().max(1) as u64;
+        let elapsed = moving_for.min(full);
+
+        // How far along the curve the cursor is, out of `full`
+        let progress = match self.curve {
+            AccelerationCurve::Linear => elapsed,
+            AccelerationCurve::Quadratic => elapsed * elapsed / full,
+        };
+
+        let range = (self.max_speed - self.min_speed) as u64;
+        (self.min_speed as u64 + range * progress / full) as i8
+    }
+}
+
+/// The cursor movement, scrolling and buttons held down through the keymap
+pub struct MouseKeys {
+    buttons: u8,
+    // How many keys are holding each direction, indexed by `MouseDirection`
+    moving: [u8; 4],
+    scrolling: [u8; 4],
+    moving_since: Instant,
+    next_move: Instant,
+    next_scroll: Instant,
+}
+
+impl MouseKeys {
+    pub const fn new() -> Self {
+        MouseKeys {
+            buttons: 0,
+            moving: [0; 4],
+            scrolling: [0; 4],
+            moving_since: Instant::from_ticks(0),
+            next_move: Instant::from_ticks(0),
+            next_scroll: Instant::from_ticks(0),
+        }
+    }
+
+    /// A report with just the buttons, for when they change
+    pub fn report(&self) -> MouseReport {
+        MouseReport {
+            buttons: self.buttons,
+            ..MouseReport::new()
+        }
+    }
+
+    pub fn press_button(&mut self, button: MouseButton) {
+        self.buttons |= button.bit();
+    }
+
+    pub fn release_button(&mut self, button: MouseButton) {
+        self.buttons &= !button.bit();
+    }
+
+    pub fn start_moving(&mut self, direction: MouseDirection, now: Instant) {
+        if self.moving == [0; 4] {
+            self.moving_since = now;
+            self.next_move = now;
+        }
+
+        self.moving[direction as usize] += 1;
+    }
+
+    pub fn stop_moving(&mut self, direction: MouseDirection) {
+        self.moving[direction as usize] = self.moving[direction as usize].saturating_sub(1);
+    }
+
+    pub fn start_scrolling(&mut self, direction: MouseDirection, now: Instant) {
+        if self.scrolling == [0; 4] {
+            self.next_scroll = now;
+        }
+
+        self.scrolling[direction as usize] += 1;
+    }
+
+    pub fn stop_scrolling(&mut self, direction: MouseDirection) {
+        self.scrolling[direction as usize] = self.scrolling[direction as usize].saturating_sub(1);
+    }
+
+    /// Moves and scrolls for any keys held, returning the report to send if it is time to
+    pub fn tick(&mut self, config: &MouseKeysConfig, now: Instant) -> Option<MouseReport> {
+        let mut report = self.report();
+        let mut changed = false;
+
+        if self.moving != [0; 4] && now >= self.next_move {
+            let moving_for = now.checked_duration_since(self.moving_since);
+            let speed = config.speed_after(moving_for.map_or(0, |d| d.to_micros()));
+
+            report.x = Self::axis(&self.moving, speed);
+            report.y = -Self::axis_vertical(&self.moving, speed);
+            self.next_move = now + MOUSE_MOVE_INTERVAL;
+            changed = true;
+        }
+
+        if self.scrolling != [0; 4] && now >= self.next_scroll {
+            let speed = config.wheel_speed as i8;
+
+            report.pan = Self::axis(&self.scrolling, speed);
+            report.wheel = Self::axis_vertical(&self.scrolling, speed);
+            self.next_scroll = now + MOUSE_WHEEL_INTERVAL;
+            changed = true;
+        }
+
+        changed.then_some(report)
+    }
+
+    /// Right is positive, and holding both directions cancels out
+    fn axis(held: &[u8; 4], speed: i8) -> i8 {
+        let left = held[MouseDirection::Left as usize] > 0;
+        let right = held[MouseDirection::Right as usize] > 0;
+        (right as i8 - left as i8) * speed
+    }
+
+    /// Up is positive, and holding both directions cancels out
+    fn axis_vertical(held: &[u8; 4], speed: i8) -> i8 {
+        let up = held[MouseDirection::Up as usize] > 0;
+        let down = held[MouseDirection::Down as usize] > 0;
+        (up as i8 - down as i8) * speed
+    }
+}
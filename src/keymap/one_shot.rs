@@ -0,0 +1,111 @@
+use crate::constants::{ONE_SHOT_TIMEOUT, TAPPING_TERM};
+use crate::keymap::{Action, LayerState};
+use crate::report::Modifiers;
+use rp2040_hal::timer::Instant;
+
+/// Modifiers and layers that, tapped on their own, apply to the next key pressed only.
+/// Tapping them twice in a row locks them on until they are tapped again.
+pub struct OneShot {
+    modifiers: Modifiers,
+    locked_modifiers: Modifiers,
+    layer: Option<u8>,
+    locked_layer: Option<u8>,
+    armed_at: Instant,
+    // Set when another key is pressed while a one-shot key is held, which makes it a normal hold
+    interrupted: bool,
+    last_tap: Option<(Action, Instant)>,
+}
+
+impl OneShot {
+    pub const fn new() -> Self {
+        OneShot {
+            modifiers: Modifiers::NONE,
+            locked_modifiers: Modifiers::NONE,
+            layer: None,
+            locked_layer: None,
+            armed_at: Instant::from_ticks(0),
+            interrupted: false,
+            last_tap: None,
+        }
+    }
+
+    /// The modifiers to add to every report sent
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers.union(self.locked_modifiers)
+    }
+
+    pub fn press(&mut self) {
+        self.interrupted = false;
+    }
+
+    pub fn interrupt(&mut self) {
+        self.interrupted = true;
+    }
+
+    /// Whether a one-shot key being released now was a tap rather than a hold used with other keys
+    pub fn is_tap(&self) -> bool {
+        !self.interrupted
+    }
+
+    pub fn tap_modifiers(&mut self, modifiers: Modifiers, now: Instant) {
+        let is_double_tap = self.is_double_tap(Action::OneShotModifiers(modifiers), now);
+
+        if self.locked_modifiers.contains(modifiers) {
+            self.locked_modifiers = self.locked_modifiers.difference(modifiers);
+        } else if is_double_tap && self.modifiers.contains(modifiers) {
+            self.modifiers = self.modifiers.difference(modifiers);
+            self.locked_modifiers = self.locked_modifiers.union(modifiers);
+        } else {
+            self.modifiers = self.modifiers.union(modifiers);
+            self.armed_at = now;
+        }
+    }
+
+    pub fn tap_layer(&mut self, layer: u8, now: Instant, layers: &mut LayerState) {
+        let is_double_tap = self.is_double_tap(Action::OneShotLayer(layer), now);
+
+        if self.locked_layer == Some(layer) {
+            self.locked_layer = None;
+            layers.toggle(layer);
+        } else if is_double_tap && self.layer == Some(layer) {
+            self.disarm_layer(layers);
+            self.locked_layer = Some(layer);
+            layers.toggle(layer);
+        } else {
+            self.disarm_layer(layers);
+            self.layer = Some(layer);
+            self.armed_at = now;
+            layers.hold(layer);
+        }
+    }
+
+    /// Uses up whatever is armed once a key has been pressed with it, returning whether the
+    /// modifiers changed
+    pub fn consume(&mut self, layers: &mut LayerState) -> bool {
+        self.disarm_layer(layers);
+        core::mem::replace(&mut self.modifiers, Modifiers::NONE) != Modifiers::NONE
+    }
+
+    /// Drops whatever has been armed for too long without being used, returning whether the
+    /// modifiers changed
+    pub fn expire(&mut self, now: Instant, layers: &mut LayerState) -> bool {
+        let is_armed = !self.modifiers.is_empty() || self.layer.is_some();
+
+        if is_armed && now >= self.armed_at + ONE_SHOT_TIMEOUT {
+            self.consume(layers)
+        } else {
+            false
+        }
+    }
+
+    fn disarm_layer(&mut self, layers: &mut LayerState) {
+        if let Some(layer) = self.layer.take() {
+            layers.release(layer);
+        }
+    }
+
+    fn is_double_tap(&mut self, action: Action, now: Instant) -> bool {
+        let last_tap = self.last_tap.replace((action, now));
+        last_tap.is_some_and(|(last, time)| last == action && now < time + TAPPING_TERM)
+    }
+}
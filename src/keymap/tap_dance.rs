@@ -0,0 +1,150 @@
+use crate::constants::{MAX_TAP_DANCE_TAPS, TAPPING_TERM};
+use crate::keymap::{Action, Event, KeyId};
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+/// A key that does something different depending on how many times it is tapped in a row, and
+/// whether the last of those taps is held
+#[derive(Copy, Clone)]
+pub struct TapDance {
+    /// What `n` taps do, a tap count past the end uses the last entry
+    taps: [Action; MAX_TAP_DANCE_TAPS],
+    tap_count: u8,
+    /// What `n` taps with the last one held do, falling back to the tap action if `NoOp`
+    holds: [Action; MAX_TAP_DANCE_TAPS],
+    /// How long to wait after each press or release for the next one
+    pub term: MicrosDurationU32,
+}
+
+impl TapDance {
+    /// A tap dance that does nothing, for the places in the keymap's table no tap dance is in
+    pub const NONE: TapDance = TapDance {
+        taps: [Action::NoOp; MAX_TAP_DANCE_TAPS],
+        tap_count: 0,
+        holds: [Action::NoOp; MAX_TAP_DANCE_TAPS],
+        term: TAPPING_TERM,
+    };
+
+    pub const fn new(taps: &[Action]) -> Self {
+        assert!(
+            !taps.is_empty() && taps.len() <= MAX_TAP_DANCE_TAPS,
+            "Tap dances need between 1 and MAX_TAP_DANCE_TAPS tap actions"
+        );
+
+        TapDance {
+            taps: copy_actions(taps),
+            tap_count: taps.len() as u8,
+            ..TapDance::NONE
+        }
+    }
+
+    pub const fn with_holds(self, holds: &[Action]) -> Self {
+        assert!(
+            holds.len() <= MAX_TAP_DANCE_TAPS,
+            "Tap dances can't have more than MAX_TAP_DANCE_TAPS hold actions"
+        );
+
+        TapDance {
+            holds: copy_actions(holds),
+            ..self
+        }
+    }
+
+    pub const fn term(self, term: MicrosDurationU32) -> Self {
+        TapDance { term, ..self }
+    }
+
+    pub fn taps(&self) -> &[Action] {
+        &self.taps[..self.tap_count as usize]
+    }
+
+    /// The hold actions, with `NoOp` where holding does the same as tapping
+    pub fn holds(&self) -> &[Action; MAX_TAP_DANCE_TAPS] {
+        &self.holds
+    }
+
+    fn tap(&self, count: u8) -> Action {
+        if self.tap_count == 0 {
+            return Action::NoOp;
+        }
+
+        let index = (count as usize).clamp(1, self.tap_count as usize) - 1;
+        self.taps[index]
+    }
+
+    fn hold(&self, count: u8) -> Action {
+        match self.holds.get(count as usize - 1) {
+            Some(&action) if action != Action::NoOp => action,
+            _ => self.tap(count),
+        }
+    }
+}
+
+/// How a finished tap dance should be played out
+pub enum TapDanceOutcome {
+    /// Pressed and released straight away
+    Tapped(Action),
+    /// Pressed until the key is released
+    Held(Action),
+}
+
+/// A tap dance key that is still counting taps
+#[derive(Copy, Clone)]
+pub struct PendingTapDance {
+    pub key: KeyId,
+    index: u8,
+    count: u8,
+    pressed: bool,
+    last_change: Instant,
+}
+
+impl PendingTapDance {
+    pub fn new(event: Event, index: u8) -> Self {
+        PendingTapDance {
+            key: event.key,
+            index,
+            count: 1,
+            pressed: true,
+            last_change: event.time,
+        }
+    }
+
+    pub fn has_expired(&self, dances: &[TapDance], now: Instant) -> bool {
+        now >= self.last_change + dances[self.index as usize].term
+    }
+
+    /// Counts a press or release of the tap dance key, returning whether the dance is over
+    pub fn update(&mut self, dances: &[TapDance], event: &Event) -> bool {
+        if event.pressed && !self.pressed {
+            self.count = self.count.saturating_add(1);
+        }
+
+        self.pressed = event.pressed;
+        self.last_change = event.time;
+
+        // Nothing left to wait for once released on the last tap with an action of its own
+        !self.pressed && self.count >= dances[self.index as usize].tap_count
+    }
+
+    pub fn outcome(&self, dances: &[TapDance]) -> TapDanceOutcome {
+        let dance = &dances[self.index as usize];
+
+        if self.pressed {
+            TapDanceOutcome::Held(dance.hold(self.count))
+        } else {
+            TapDanceOutcome::Tapped(dance.tap(self.count))
+        }
+    }
+}
+
+/// Pads the actions out with `NoOp` to fill a tap dance's table
+const fn copy_actions(actions: &[Action]) -> [Action; MAX_TAP_DANCE_TAPS] {
+    let mut copied = [Action::NoOp; MAX_TAP_DANCE_TAPS];
+    let mut i = 0;
+    while i < actions.len() {
+        copied[i] = actions[i];
+        i += 1;
+    }
+
+    copied
+}
@@ -0,0 +1,221 @@
+use crate::common::Queue;
+use crate::constants::{TAPPING_TERM, TAP_HOLD_BUFFER_SIZE};
+use crate::keymap::{Action, Event, KeyId};
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+/// How a tap-hold key decides between its two actions
+#[derive(Copy, Clone)]
+pub struct TapHoldConfig {
+    /// Held for at least this long, the key is always a hold
+    pub tapping_term: MicrosDurationU32,
+    /// Another key pressed and released while this one is down makes it a hold
+    pub permissive_hold: bool,
+    /// Any other key pressed while this one is down makes it a hold
+    pub hold_on_other_key_press: bool,
+}
+
+impl TapHoldConfig {
+    pub const DEFAULT: TapHoldConfig = TapHoldConfig {
+        tapping_term: TAPPING_TERM,
+        permissive_hold: false,
+        hold_on_other_key_press: false,
+    };
+
+    pub const fn tapping_term(self, tapping_term: MicrosDurationU32) -> Self {
+        TapHoldConfig {
+            tapping_term,
+            ..self
+        }
+    }
+
+    pub const fn permissive_hold(self) -> Self {
+        TapHoldConfig {
+            permissive_hold: true,
+            ..self
+        }
+    }
+
+    pub const fn hold_on_other_key_press(self) -> Self {
+        TapHoldConfig {
+            hold_on_other_key_press: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TapHoldDecision {
+    Tap,
+    Hold,
+}
+
+/// A tap-hold key that has been pressed but not decided yet
+#[derive(Copy, Clone)]
+pub struct PendingTapHold {
+    pub key: KeyId,
+    action: Action,
+    config: TapHoldConfig,
+    pressed_at: Instant,
+}
+
+impl PendingTapHold {
+    pub fn new(event: Event, action: Action, config: TapHoldConfig) -> Self {
+        PendingTapHold {
+            key: event.key,
+            action,
+            config,
+            pressed_at: event.time,
+        }
+    }
+
+    pub fn has_expired(&self, now: Instant) -> bool {
+        now >= self.pressed_at + self.config.tapping_term
+    }
+
+    /// Decides the key given the next event, which has already been added to the buffered events
+    pub fn decide(
+        &self,
+        event: &Event,
+        buffered: &Queue<Event, TAP_HOLD_BUFFER_SIZE>,
+    ) -> Option<TapHoldDecision> {
+        let is_self = event.key == self.key;
+
+        if self.has_expired(event.time) {
+            Some(TapHoldDecision::Hold)
+        } else if is_self && !event.pressed {
+            Some(TapHoldDecision::Tap)
+        } else if is_self {
+            None
+        } else if event.pressed && self.config.hold_on_other_key_press {
+            Some(TapHoldDecision::Hold)
+        } else if !event.pressed
+            && self.config.permissive_hold
+            && buffered.iter().any(|e| e.pressed && e.key == event.key)
+        {
+            // Only keys pressed after this one count, a release of something already held doesn't
+            Some(TapHoldDecision::Hold)
+        } else {
+            None
+        }
+    }
+
+    pub fn resolve(&self, decision: TapHoldDecision) -> Action {
+        match (self.action, decision) {
+            (Action::ModTap(_, key), TapHoldDecision::Tap) => Action::Key(key),
+            (Action::ModTap(modifiers, _), TapHoldDecision::Hold) => Action::Modifiers(modifiers),
+            (Action::LayerTap(_, key), TapHoldDecision::Tap) => Action::Key(key),
+            (Action::LayerTap(layer, _), TapHoldDecision::Hold) => Action::MomentaryLayer(layer),
+            (action, _) => action,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Modifiers;
+    use usbd_human_interface_device::page::Keyboard;
+
+    const CTL_ESC: KeyId = KeyId::Matrix { row: 2, col: 0 };
+    const G: KeyId = KeyId::Matrix { row: 2, col: 5 };
+
+    fn event(millis: u64, key: KeyId, pressed: bool) -> Event {
+        Event {
+            key,
+            pressed,
+            time: Instant::from_ticks(millis * 1000),
+        }
+    }
+
+    fn pending(config: TapHoldConfig) -> PendingTapHold {
+        let action = Action::ModTap(Modifiers::LEFT_CTRL, Keyboard::Escape);
+        PendingTapHold::new(event(0, CTL_ESC, true), action, config)
+    }
+
+    /// Presses the tap-hold key at 0ms and plays out the script of (millis, key, pressed) the way
+    /// the engine does, returning the decision and when it was made
+    fn run(config: TapHoldConfig, script: &[(u64, KeyId, bool)]) -> Option<(u64, TapHoldDecision)> {
+        let pending = pending(config);
+        let mut buffered = Queue::new();
+
+        script.iter().find_map(|&(millis, key, pressed)| {
+            let event = event(millis, key, pressed);
+            buffered.push(event).unwrap();
+            pending
+                .decide(&event, &buffered)
+                .map(|decision| (millis, decision))
+        })
+    }
+
+    #[test]
+    fn released_within_the_tapping_term_is_a_tap() {
+        let decision = run(TapHoldConfig::DEFAULT, &[(150, CTL_ESC, false)]);
+        assert_eq!(decision, Some((150, TapHoldDecision::Tap)));
+    }
+
+    #[test]
+    fn held_past_the_tapping_term_is_a_hold() {
+        let pending = pending(TapHoldConfig::DEFAULT);
+        assert!(!pending.has_expired(Instant::from_ticks(199_999)));
+        assert!(pending.has_expired(Instant::from_ticks(200_000)));
+
+        let decision = run(TapHoldConfig::DEFAULT, &[(250, CTL_ESC, false)]);
+        assert_eq!(decision, Some((250, TapHoldDecision::Hold)));
+    }
+
+    #[test]
+    fn the_tapping_term_can_be_changed_per_key() {
+        let config = TapHoldConfig::DEFAULT.tapping_term(MicrosDurationU32::millis(300));
+        let decision = run(config, &[(250, CTL_ESC, false)]);
+        assert_eq!(decision, Some((250, TapHoldDecision::Tap)));
+    }
+
+    #[test]
+    fn by_default_a_key_rolled_over_within_the_term_leaves_a_tap() {
+        let script = [(50, G, true), (80, G, false), (120, CTL_ESC, false)];
+        let decision = run(TapHoldConfig::DEFAULT, &script);
+        assert_eq!(decision, Some((120, TapHoldDecision::Tap)));
+    }
+
+    #[test]
+    fn permissive_hold_holds_when_another_key_is_tapped_inside() {
+        let config = TapHoldConfig::DEFAULT.permissive_hold();
+        let script = [(50, G, true), (80, G, false), (120, CTL_ESC, false)];
+        assert_eq!(run(config, &script), Some((80, TapHoldDecision::Hold)));
+    }
+
+    #[test]
+    fn permissive_hold_ignores_a_key_released_after_this_one() {
+        let config = TapHoldConfig::DEFAULT.permissive_hold();
+        let script = [(50, G, true), (80, CTL_ESC, false), (120, G, false)];
+        assert_eq!(run(config, &script), Some((80, TapHoldDecision::Tap)));
+    }
+
+    #[test]
+    fn permissive_hold_ignores_a_key_held_from_before() {
+        let config = TapHoldConfig::DEFAULT.permissive_hold();
+        let script = [(50, G, false), (120, CTL_ESC, false)];
+        assert_eq!(run(config, &script), Some((120, TapHoldDecision::Tap)));
+    }
+
+    #[test]
+    fn hold_on_other_key_press_holds_as_soon_as_another_key_goes_down() {
+        let config = TapHoldConfig::DEFAULT.hold_on_other_key_press();
+        let script = [(50, G, true), (80, G, false), (120, CTL_ESC, false)];
+        assert_eq!(run(config, &script), Some((50, TapHoldDecision::Hold)));
+    }
+
+    #[test]
+    fn decisions_resolve_to_the_key_or_the_modifiers() {
+        let pending = pending(TapHoldConfig::DEFAULT);
+        assert_eq!(
+            pending.resolve(TapHoldDecision::Tap),
+            Action::Key(Keyboard::Escape)
+        );
+        assert_eq!(
+            pending.resolve(TapHoldDecision::Hold),
+            Action::Modifiers(Modifiers::LEFT_CTRL)
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::keymap::MacroStep;
+use crate::report::Modifiers;
+use usbd_human_interface_device::page::Keyboard;
+
+/// How the host expects code points to be typed in
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnicodeMode {
+    /// IBus and GTK: Ctrl+Shift+U, the hex digits, then Space
+    Linux,
+    /// The Unicode Hex Input keyboard layout: the hex digits of each UTF-16 unit with Option held
+    MacOs,
+    /// WinCompose: the compose key, U, the hex digits, then Enter
+    WinCompose,
+}
+
+impl UnicodeMode {
+    pub const DEFAULT: UnicodeMode = UnicodeMode::Linux;
+
+    /// Saved to flash, so the numbers can't change
+    pub const fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn from_id(id: u8) -> Option<UnicodeMode> {
+        match id {
+            0 => Some(UnicodeMode::Linux),
+            1 => Some(UnicodeMode::MacOs),
+            2 => Some(UnicodeMode::WinCompose),
+            _ => None,
+        }
+    }
+}
+
+// Two UTF-16 units of four digits each, plus the Option presses around them
+const MAX_STEPS: usize = 10;
+
+/// The steps typing a code point goes through, worked out again each time one is needed so
+/// nothing has to be stored per character
+struct Sequence {
+    steps: [MacroStep; MAX_STEPS],
+    len: usize,
+}
+
+impl Sequence {
+    fn new() -> Self {
+        Sequence {
+            steps: [MacroStep::Tap(Keyboard::NoEventIndicated); MAX_STEPS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, step: MacroStep) {
+        self.steps[self.len] = step;
+        self.len += 1;
+    }
+
+    /// The hex digits of a value, padded with zeros to at least `min_digits`
+    fn push_hex(&mut self, value: u32, min_digits: u32) {
+        let digits = (u32::BITS - value.leading_zeros())
+            .div_ceil(4)
+            .max(min_digits);
+
+        for digit in (0..digits).rev() {
+            self.push(MacroStep::Tap(hex_key((value >> (digit * 4)) as u8 & 0xF)));
+        }
+    }
+}
+
+fn hex_key(digit: u8) -> Keyboard {
+    match digit {
+        0 => Keyboard::Keyboard0,
+        1..=9 => Keyboard::from(u8::from(Keyboard::Keyboard1) + digit - 1),
+        _ => Keyboard::from(u8::from(Keyboard::A) + digit - 10),
+    }
+}
+
+/// The step at an index of typing a code point, or nothing once it has all been typed
+pub fn step(character: char, mode: UnicodeMode, index: usize) -> Option<MacroStep> {
+    let mut sequence = Sequence::new();
+
+    match mode {
+        UnicodeMode::Linux => {
+            sequence.push(MacroStep::PressModifiers(
+                Modifiers::LEFT_CTRL.union(Modifiers::LEFT_SHIFT),
+            ));
+            sequence.push(MacroStep::Tap(Keyboard::U));
+            sequence.push(MacroStep::ReleaseModifiers(
+                Modifiers::LEFT_CTRL.union(Modifiers::LEFT_SHIFT),
+            ));
+            sequence.push_hex(character as u32, 4);
+            sequence.push(MacroStep::Tap(Keyboard::Space));
+        }
+        UnicodeMode::MacOs => {
+            sequence.push(MacroStep::PressModifiers(Modifiers::LEFT_ALT));
+            for unit in character.encode_utf16(&mut [0; 2]) {
+                sequence.push_hex(*unit as u32, 4);
+            }
+            sequence.push(MacroStep::ReleaseModifiers(Modifiers::LEFT_ALT));
+        }
+        UnicodeMode::WinCompose => {
+            sequence.push(MacroStep::Tap(Keyboard::RightAlt));
+            sequence.push(MacroStep::Tap(Keyboard::U));
+            sequence.push_hex(character as u32, 4);
+            sequence.push(MacroStep::Tap(Keyboard::ReturnEnter));
+        }
+    }
+
+    sequence.steps[..sequence.len].get(index).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(character: char, mode: UnicodeMode) -> Vec<MacroStep> {
+        (0..)
+            .map_while(|index| step(character, mode, index))
+            .collect()
+    }
+
+    fn taps(keys: &[Keyboard]) -> impl Iterator<Item = MacroStep> + '_ {
+        keys.iter().map(|&key| MacroStep::Tap(key))
+    }
+
+    #[test]
+    fn linux_types_ctrl_shift_u_then_the_code_point() {
+        let ctrl_shift = Modifiers::LEFT_CTRL.union(Modifiers::LEFT_SHIFT);
+        let mut expected = vec![
+            MacroStep::PressModifiers(ctrl_shift),
+            MacroStep::Tap(Keyboard::U),
+            MacroStep::ReleaseModifiers(ctrl_shift),
+        ];
+        expected.extend(taps(&[
+            Keyboard::Keyboard2,
+            Keyboard::Keyboard0,
+            Keyboard::A,
+            Keyboard::C,
+            Keyboard::Space,
+        ]));
+
+        assert_eq!(steps('€', UnicodeMode::Linux), expected);
+    }
+
+    #[test]
+    fn macos_holds_option_over_the_code_point() {
+        let mut expected = vec![MacroStep::PressModifiers(Modifiers::LEFT_ALT)];
+        expected.extend(taps(&[
+            Keyboard::Keyboard0,
+            Keyboard::Keyboard0,
+            Keyboard::E,
+            Keyboard::Keyboard9,
+        ]));
+        expected.push(MacroStep::ReleaseModifiers(Modifiers::LEFT_ALT));
+
+        assert_eq!(steps('é', UnicodeMode::MacOs), expected);
+    }
+
+    #[test]
+    fn macos_types_code_points_past_the_first_plane_as_surrogates() {
+        let mut expected = vec![MacroStep::PressModifiers(Modifiers::LEFT_ALT)];
+        expected.extend(taps(&[
+            Keyboard::D,
+            Keyboard::Keyboard8,
+            Keyboard::Keyboard3,
+            Keyboard::D,
+            Keyboard::D,
+            Keyboard::E,
+            Keyboard::Keyboard0,
+            Keyboard::Keyboard0,
+        ]));
+        expected.push(MacroStep::ReleaseModifiers(Modifiers::LEFT_ALT));
+
+        assert_eq!(steps('😀', UnicodeMode::MacOs), expected);
+    }
+
+    #[test]
+    fn wincompose_types_compose_u_then_the_code_point() {
+        let expected: Vec<_> = taps(&[
+            Keyboard::RightAlt,
+            Keyboard::U,
+            Keyboard::Keyboard1,
+            Keyboard::F,
+            Keyboard::Keyboard6,
+            Keyboard::Keyboard0,
+            Keyboard::Keyboard0,
+            Keyboard::ReturnEnter,
+        ])
+        .collect();
+
+        assert_eq!(steps('😀', UnicodeMode::WinCompose), expected);
+    }
+
+    #[test]
+    fn code_points_get_as_many_digits_as_they_need() {
+        let digits = |character| steps(character, UnicodeMode::Linux)[3..].to_vec();
+
+        assert_eq!(
+            digits('\u{1F600}'),
+            taps(&[
+                Keyboard::Keyboard1,
+                Keyboard::F,
+                Keyboard::Keyboard6,
+                Keyboard::Keyboard0,
+                Keyboard::Keyboard0,
+                Keyboard::Space,
+            ])
+            .collect::<Vec<_>>()
+        );
+        // The highest code point there is still fits in the steps kept
+        assert_eq!(
+            digits('\u{10FFFF}'),
+            taps(&[
+                Keyboard::Keyboard1,
+                Keyboard::Keyboard0,
+                Keyboard::F,
+                Keyboard::F,
+                Keyboard::F,
+                Keyboard::F,
+                Keyboard::Space,
+            ])
+            .collect::<Vec<_>>()
+        );
+    }
+}
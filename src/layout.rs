@@ -0,0 +1,73 @@
+//! Where each key physically sits on the board, generated by build.rs from the KLE layout in
+//! layout.json
+
+/// Positions and sizes are in hundredths of the width of a standard key
+pub const KEY_UNIT: u16 = 100;
+
+/// A key in the matrix and where it is, measured from the top left corner of the board
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyPosition {
+    pub row: u8,
+    pub col: u8,
+    /// To the key's left edge
+    pub x: u16,
+    /// To the key's top edge
+    pub y: u16,
+    pub width: u16,
+}
+
+impl KeyPosition {
+    /// The middle of the key, for effects that spread out across the board
+    pub const fn centre(&self) -> (u16, u16) {
+        (self.x + self.width / 2, self.y + KEY_UNIT / 2)
+    }
+}
+
+// Every key in the order they appear on the board, row by row from the top left
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+/// The key over an LED. The strip snakes through the rows, left to right along the top row then
+/// back right to left along the next.
+pub fn led_key(led: usize) -> Option<&'static KeyPosition> {
+    let key = KEY_POSITIONS.get(led)?;
+    if key.row % 2 == 0 {
+        return Some(key);
+    }
+
+    let first = KEY_POSITIONS
+        .iter()
+        .position(|other| other.row == key.row)?;
+    let last = KEY_POSITIONS
+        .iter()
+        .rposition(|other| other.row == key.row)?;
+    KEY_POSITIONS.get(first + last - led)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{CAPS_LOCK_LED, NUMBER_OF_LEDS};
+
+    #[test]
+    fn every_led_is_under_a_different_key() {
+        let mut keys: Vec<_> = (0..NUMBER_OF_LEDS)
+            .map(|led| led_key(led).map(|key| (key.row, key.col)))
+            .collect();
+        keys.sort();
+        keys.dedup();
+
+        assert_eq!(keys.len(), NUMBER_OF_LEDS);
+        assert!(keys.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn leds_run_back_along_every_other_row() {
+        let key = |led| led_key(led).map(|key| (key.row, key.col));
+
+        assert_eq!(key(0), Some((0, 0)));
+        assert_eq!(key(14), Some((0, 14)));
+        assert_eq!(key(15), Some((1, 14)));
+        assert_eq!(key(29), Some((1, 0)));
+        assert_eq!(key(CAPS_LOCK_LED), Some((2, 0)));
+    }
+}
@@ -1,14 +1,26 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(generic_const_exprs)]
+// The feature is still marked incomplete, though the little used of it here works
+#![allow(incomplete_features)]
 
 mod common;
 mod constants;
+mod debounce;
 mod hal;
 mod keyboard;
+mod keymap;
+mod layout;
+mod nkro_keyboard;
+mod raw_hid;
+mod report;
 mod rgb;
+mod settings;
+mod storage;
+mod system_control;
+mod via;
+mod vial;
 
-use core::panic::PanicInfo;
 use cortex_m::prelude::_embedded_hal_timer_CountDown;
 use hal::{
     hal::{
@@ -16,44 +28,50 @@ use hal::{
         dma::DMAExt,
         pac,
         pio::PIOExt,
-        rom_data::reset_to_usb_boot,
         watchdog::Watchdog,
         Sio,
     },
     XOSC_CRYSTAL_FREQ,
 };
-use rp2040_hal::fugit::ExtU32;
 use usb_device::class_prelude::UsbBusAllocator;
 use usb_device::prelude::{StringDescriptors, UsbDeviceBuilder, UsbVidPid};
 use usb_device::UsbError;
-use usbd_human_interface_device::descriptor::InterfaceProtocol;
-use usbd_human_interface_device::device::keyboard::{
-    NKROBootKeyboardConfig, NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
-};
-use usbd_human_interface_device::interface::{InterfaceBuilder, ManagedIdleInterfaceConfig};
+use usbd_human_interface_device::device::consumer::{ConsumerControl, ConsumerControlConfig};
+use usbd_human_interface_device::device::mouse::{WheelMouse, WheelMouseConfig};
+use usbd_human_interface_device::device::DeviceClass;
 use usbd_human_interface_device::prelude::UsbHidClassBuilder;
 use usbd_human_interface_device::UsbHidError;
 
 use keyboard::KeyboardInputManager;
+use nkro_keyboard::{NkroKeyboard, NkroKeyboardConfig};
+use raw_hid::{RawHid, RawHidConfig};
 use rgb::{RGBBufferManager, RGBController, RGBEffectResult};
+use settings::Settings;
+use storage::QspiFlash;
+use system_control::{SystemControl, SystemControlConfig};
+use vial::Vial;
 
 use crate::common::ClampedTimer;
 use crate::constants::{
-    EFFECT_RATE, HID_TICK_RATE, KEYBOARD_POLLING_RATE, ROWS_PER_POLL, USB_ENDPOINT_POLL_RATE,
+    CAPS_LOCK_LED, DEBOUNCE_ALGORITHM, DEBOUNCE_KEY_TIMINGS, DEBOUNCE_TIME, EFFECT_RATE,
+    HID_TICK_RATE, KEYBOARD_POLLING_RATE, ROWS_PER_POLL,
 };
-use crate::hal::entry;
-use crate::keyboard::{BasicKeymap, KeyMap};
-use crate::rgb::{RGBEffect, UnicornBarfWaveEffect};
+use crate::debounce::{AnyDebouncer, DebounceConfig, DebounceTiming};
+use crate::keymap::{BasicKeymap, KeymapEngine};
+use crate::rgb::{Color, LightingEffect, LightingSettings, RGBEffect, UnicornBarfWaveEffect};
 use constants::RESET_DELAY;
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    reset_to_usb_boot(0, 0);
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    hal::hal::rom_data::reset_to_usb_boot(0, 0);
 
     loop {}
 }
 
-#[entry]
+// The tests bring their own main, and so leave this one unused
+#[cfg_attr(not(test), hal::hal::entry)]
+#[cfg_attr(test, allow(dead_code))]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
@@ -123,28 +141,21 @@ fn main() -> ! {
         &mut pac.RESETS,
     ));
 
-    let config = NKROBootKeyboardConfig::new(ManagedIdleInterfaceConfig::new(
-        InterfaceBuilder::new(NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR)
-            .unwrap()
-            .description("It's the Daudboard. What more could you want?")
-            .boot_device(InterfaceProtocol::Keyboard)
-            .idle_default(500.millis())
-            .unwrap()
-            .in_endpoint(USB_ENDPOINT_POLL_RATE.into_duration())
-            .unwrap()
-            .with_out_endpoint(100.millis())
-            .unwrap()
-            .build(),
-    ));
-
-    let mut keyboard = UsbHidClassBuilder::new().add_device(config).build(&usb_bus);
+    let mut keyboard = UsbHidClassBuilder::new()
+        .add_device(NkroKeyboardConfig::default())
+        .add_device(WheelMouseConfig::default())
+        .add_device(ConsumerControlConfig::default())
+        .add_device(SystemControlConfig::default())
+        .add_device(RawHidConfig::default())
+        .build(&usb_bus);
 
     //https://pid.codes
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
         .strings(&[StringDescriptors::default()
             .manufacturer("Daudi")
             .product("The Daudboard")
-            .serial_number("1")])
+            // Vial finds keyboards it can talk to by this serial number
+            .serial_number("vial:f64c2b3c")])
         .unwrap()
         .build();
 
@@ -174,8 +185,26 @@ fn main() -> ! {
         pins.col15.reconfigure(),
     );
 
+    let mut debounce_config = DebounceConfig::uniform(DebounceTiming::symmetric(DEBOUNCE_TIME));
+    for &(row, col, timing) in DEBOUNCE_KEY_TIMINGS {
+        debounce_config = debounce_config.with_key(row, col, timing);
+    }
+    let debouncer = AnyDebouncer::new(DEBOUNCE_ALGORITHM, debounce_config);
+
     let mut input_manager =
-        KeyboardInputManager::initialise(row_pin_group, col_pin_group).activate();
+        KeyboardInputManager::initialise(row_pin_group, col_pin_group).activate(debouncer);
+
+    let mut keymap = KeymapEngine::<5, 15, BasicKeymap>::new();
+    let mut pending_report = None;
+    let mut pending_mouse_report = None;
+    let mut pending_consumer_report = None;
+    let mut pending_system_report = None;
+    let mut pending_raw_hid_report = None;
+    let mut lighting = LightingSettings::DEFAULT;
+    let mut vial = Vial::new();
+
+    let mut settings = Settings::new(QspiFlash::new());
+    settings.load(&mut keymap, &mut lighting);
 
     // Keyboard timers
     let mut tick_count_down = timer.count_down();
@@ -189,14 +218,91 @@ fn main() -> ! {
         {
             // Check the keyboard input
             if poll_timer.wait().is_ok() {
-                if let Some(key_buff_copy) = input_manager.continue_polling() {
+                let now = timer.get_counter();
+                input_manager.continue_polling(now);
+
+                while let Some(event) = input_manager.next_event() {
+                    keymap.process(event);
+                }
+                keymap.tick(now);
+                settings.save_when_settled(&keymap, &lighting, now);
+
+                if pending_report.is_none() {
+                    pending_report = keymap.next_report();
+                }
+
+                if let Some(report) = pending_report {
+                    // Turning NKRO off only limits how many keys are reported. The report stays in
+                    // the NKRO layout the keyboard describes, and hosts that can only read boot
+                    // reports get them by selecting the boot protocol.
+                    let report = if keymap.is_nkro() {
+                        report
+                    } else {
+                        report.with_six_keys()
+                    };
+                    let result = keyboard
+                        .device::<NkroKeyboard<'_, _>, _>()
+                        .write_report(&report);
+
+                    match result {
+                        Ok(_) => pending_report = None,
+                        Err(UsbHidError::WouldBlock) => {}
+                        Err(UsbHidError::Duplicate) => pending_report = None,
+                        Err(_) => panic!(),
+                    }
+                }
+
+                if pending_mouse_report.is_none() {
+                    pending_mouse_report = keymap.next_mouse_report();
+                }
+
+                if let Some(report) = pending_mouse_report {
                     match keyboard
-                        .device()
-                        .write_report(BasicKeymap::transform(key_buff_copy))
+                        .device::<WheelMouse<'_, _>, _>()
+                        .write_report(&report.into())
                     {
-                        Ok(_) => {}
+                        Ok(_) => pending_mouse_report = None,
                         Err(UsbHidError::WouldBlock) => {}
-                        Err(UsbHidError::Duplicate) => {}
+                        Err(_) => panic!(),
+                    }
+                }
+
+                if pending_consumer_report.is_none() {
+                    pending_consumer_report = keymap.next_consumer_report();
+                }
+
+                if let Some(report) = pending_consumer_report {
+                    // Written raw, as the crate's own report can't hold every usage on the page
+                    match keyboard
+                        .device::<ConsumerControl<'_, _>, _>()
+                        .interface()
+                        .write_report(&report.to_bytes())
+                    {
+                        Ok(_) => pending_consumer_report = None,
+                        Err(UsbError::WouldBlock) => {}
+                        Err(_) => panic!(),
+                    }
+                }
+
+                if pending_system_report.is_none() {
+                    pending_system_report = keymap.next_system_report();
+                }
+
+                if let Some(report) = pending_system_report {
+                    match keyboard
+                        .device::<SystemControl<'_, _>, _>()
+                        .write_report(&report)
+                    {
+                        Ok(_) => pending_system_report = None,
+                        Err(UsbError::WouldBlock) => {}
+                        Err(_) => panic!(),
+                    }
+                }
+
+                if let Some(report) = pending_raw_hid_report {
+                    match keyboard.device::<RawHid<'_, _>, _>().write_report(&report) {
+                        Ok(_) => pending_raw_hid_report = None,
+                        Err(UsbError::WouldBlock) => {}
                         Err(_) => panic!(),
                     }
                 }
@@ -206,7 +312,7 @@ fn main() -> ! {
         {
             // Check the usb poller
             if usb_dev.poll(&mut [&mut keyboard]) {
-                match keyboard.device().read_report() {
+                match keyboard.device::<NkroKeyboard<'_, _>, _>().read_report() {
                     Err(UsbError::WouldBlock) => {
                         //do nothing
                     }
@@ -217,6 +323,28 @@ fn main() -> ! {
                         // TODO create an effect that can use this
                     }
                 }
+
+                // A command from VIA, answered once the last reply has been sent
+                if pending_raw_hid_report.is_none() {
+                    match keyboard.device::<RawHid<'_, _>, _>().read_report() {
+                        Err(UsbError::WouldBlock) => {}
+                        Err(e) => {
+                            panic!("Failed to read raw HID report: {:?}", e)
+                        }
+                        Ok(mut report) => {
+                            via::process(
+                                &mut report,
+                                &mut keymap,
+                                &input_manager.matrix(),
+                                &mut lighting,
+                                &mut vial,
+                                timer.get_counter(),
+                            );
+                            pending_raw_hid_report = Some(report);
+                            settings.changed(timer.get_counter());
+                        }
+                    }
+                }
             }
         }
 
@@ -238,7 +366,16 @@ fn main() -> ! {
                 (true, RGBEffectResult::Finished(stalled, mut buf_man)) => {
                     delay_timer.restart();
                     if effect_timer.wait().is_ok() {
-                        effect.apply_effect(&mut buf_man);
+                        match lighting.effect {
+                            LightingEffect::Off => buf_man.fill(Color::OFF),
+                            LightingEffect::Solid => buf_man.fill(lighting.color()),
+                            LightingEffect::RainbowWave => effect.apply_effect(&mut buf_man),
+                        }
+                        buf_man.dim(lighting.brightness);
+
+                        if keymap.is_caps_word_active() {
+                            buf_man.set(CAPS_LOCK_LED, Color::rgb(0x40, 0x40, 0x40));
+                        }
                     }
 
                     stalled.start_pattern(buf_man).wait()
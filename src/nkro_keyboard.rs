@@ -0,0 +1,118 @@
+use rp2040_hal::fugit::{ExtU32, MillisDurationU32};
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR;
+use usbd_human_interface_device::usb_class::prelude::{
+    DeviceClass, HidProtocol, InBytes32, Interface, InterfaceBuilder, InterfaceConfig,
+    InterfaceProtocol, OutBytes8, ReportSingle, UsbAllocatable, UsbHidError,
+};
+
+use crate::constants::USB_ENDPOINT_POLL_RATE;
+use crate::report::KeyboardReport;
+
+/// The crate's NKRO keyboard, except that hosts which selected the boot protocol get the 8 byte
+/// boot report. The crate's own sends the whole NKRO report whatever the protocol, which a host
+/// expecting 8 bytes can't read.
+pub struct NkroKeyboard<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes32, OutBytes8, ReportSingle>,
+    // Sent again whenever the host's idle rate comes round without a change
+    last_report: Option<KeyboardReport>,
+    since_last_report: MillisDurationU32,
+}
+
+impl<B: UsbBus> NkroKeyboard<'_, B> {
+    /// Sends the keys held in whichever layout the host selected, unless they haven't changed
+    pub fn write_report(&mut self, report: &KeyboardReport) -> Result<(), UsbHidError> {
+        if self.last_report == Some(*report) {
+            return Err(UsbHidError::Duplicate);
+        }
+        self.send(report)
+    }
+
+    /// The lock key LEDs the host last set
+    pub fn read_report(&mut self) -> usb_device::Result<u8> {
+        let mut leds = [0];
+        self.interface.read_report(&mut leds)?;
+        Ok(leds[0])
+    }
+
+    fn send(&mut self, report: &KeyboardReport) -> Result<(), UsbHidError> {
+        let written = if self.interface.protocol() == HidProtocol::Boot {
+            self.interface.write_report(&report.to_boot_bytes())
+        } else {
+            self.interface.write_report(&report.to_nkro_bytes())
+        };
+
+        written.map_err(UsbHidError::from)?;
+        self.last_report = Some(*report);
+        self.since_last_report = 0.millis();
+        Ok(())
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for NkroKeyboard<'a, B> {
+    type I = Interface<'a, B, InBytes32, OutBytes8, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {
+        self.last_report = None;
+        self.since_last_report = 0.millis();
+    }
+
+    /// Called every millisecond
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        let idle = self.interface.global_idle();
+
+        // An idle rate of zero asks for reports only when something changes
+        if idle.ticks() == 0 {
+            self.since_last_report = 0.millis();
+            return Ok(());
+        }
+
+        if self.since_last_report < idle {
+            self.since_last_report += 1.millis();
+            return Ok(());
+        }
+
+        match self.last_report {
+            Some(report) => self.send(&report),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct NkroKeyboardConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes32, OutBytes8, ReportSingle>,
+}
+
+impl Default for NkroKeyboardConfig<'_> {
+    fn default() -> Self {
+        NkroKeyboardConfig {
+            interface: InterfaceBuilder::new(NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR)
+                .unwrap()
+                .description("It's the Daudboard. What more could you want?")
+                .boot_device(InterfaceProtocol::Keyboard)
+                .idle_default(500.millis())
+                .unwrap()
+                .in_endpoint(USB_ENDPOINT_POLL_RATE.into_duration())
+                .unwrap()
+                .with_out_endpoint(100.millis())
+                .unwrap()
+                .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for NkroKeyboardConfig<'a> {
+    type Allocated = NkroKeyboard<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        NkroKeyboard {
+            interface: Interface::new(usb_alloc, self.interface),
+            last_report: None,
+            since_last_report: 0.millis(),
+        }
+    }
+}
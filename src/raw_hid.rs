@@ -0,0 +1,92 @@
+use rp2040_hal::fugit::ExtU32;
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::usb_class::prelude::{
+    DeviceClass, InBytes32, Interface, InterfaceBuilder, InterfaceConfig, OutBytes32, ReportSingle,
+    UsbAllocatable, UsbHidError,
+};
+
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+/// 32 bytes each way on the vendor defined page VIA looks for
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60),
+    0x09, 0x61,       // Usage (0x61),
+    0xA1, 0x01,       // Collection (Application),
+    0x09, 0x62,       //     Usage (Data In),
+    0x15, 0x00,       //     Logical Minimum (0),
+    0x26, 0xFF, 0x00, //     Logical Maximum (255),
+    0x75, 0x08,       //     Report Size (8),
+    0x95, 0x20,       //     Report Count (32),
+    0x81, 0x02,       //     Input (Data, Variable, Absolute),
+    0x09, 0x63,       //     Usage (Data Out),
+    0x15, 0x00,       //     Logical Minimum (0),
+    0x26, 0xFF, 0x00, //     Logical Maximum (255),
+    0x75, 0x08,       //     Report Size (8),
+    0x95, 0x20,       //     Report Count (32),
+    0x91, 0x02,       //     Output (Data, Variable, Absolute),
+    0xC0,             // End Collection
+];
+
+/// Fixed size reports sent back and forth with the host, which is how VIA talks to keyboards
+pub struct RawHid<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes32, OutBytes32, ReportSingle>,
+}
+
+impl<B: UsbBus> RawHid<'_, B> {
+    pub fn write_report(
+        &mut self,
+        report: &[u8; RAW_HID_REPORT_SIZE],
+    ) -> usb_device::Result<usize> {
+        self.interface.write_report(report)
+    }
+
+    pub fn read_report(&mut self) -> usb_device::Result<[u8; RAW_HID_REPORT_SIZE]> {
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        self.interface.read_report(&mut report)?;
+        Ok(report)
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for RawHid<'a, B> {
+    type I = Interface<'a, B, InBytes32, OutBytes32, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct RawHidConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes32, OutBytes32, ReportSingle>,
+}
+
+impl Default for RawHidConfig<'_> {
+    fn default() -> Self {
+        RawHidConfig {
+            interface: InterfaceBuilder::new(RAW_HID_REPORT_DESCRIPTOR)
+                .unwrap()
+                .description("Raw HID")
+                .in_endpoint(1.millis())
+                .unwrap()
+                .with_out_endpoint(1.millis())
+                .unwrap()
+                .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for RawHidConfig<'a> {
+    type Allocated = RawHid<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        RawHid {
+            interface: Interface::new(usb_alloc, self.interface),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use usbd_human_interface_device::device::mouse::WheelMouseReport;
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+/// A set of modifier keys, laid out like the modifier byte of a boot keyboard report
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const LEFT_CTRL: Modifiers = Modifiers(1 << 0);
+    pub const LEFT_SHIFT: Modifiers = Modifiers(1 << 1);
+    pub const LEFT_ALT: Modifiers = Modifiers(1 << 2);
+    pub const LEFT_GUI: Modifiers = Modifiers(1 << 3);
+    pub const RIGHT_CTRL: Modifiers = Modifiers(1 << 4);
+    pub const RIGHT_SHIFT: Modifiers = Modifiers(1 << 5);
+    pub const RIGHT_ALT: Modifiers = Modifiers(1 << 6);
+    pub const RIGHT_GUI: Modifiers = Modifiers(1 << 7);
+
+    pub const fn union(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The modifier byte of a boot keyboard report
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u8) -> Modifiers {
+        Modifiers(bits)
+    }
+
+    pub fn keys(self) -> impl Iterator<Item = Keyboard> {
+        (0..u8::BITS as u8)
+            .filter(move |bit| self.0 & (1 << bit) != 0)
+            .map(|bit| Keyboard::from(u8::from(Keyboard::LeftControl) + bit))
+    }
+}
+
+/// The set of keyboard usages currently held down, kept as a bitmap over the whole usage page
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct KeyboardReport {
+    usages: [u32; 8],
+}
+
+impl KeyboardReport {
+    pub const fn new() -> Self {
+        KeyboardReport { usages: [0; 8] }
+    }
+
+    pub fn press(&mut self, key: Keyboard) {
+        if key != Keyboard::NoEventIndicated {
+            let usage = u8::from(key);
+            self.usages[(usage / 32) as usize] |= 1 << (usage % 32);
+        }
+    }
+
+    pub fn release(&mut self, key: Keyboard) {
+        let usage = u8::from(key);
+        self.usages[(usage / 32) as usize] &= !(1 << (usage % 32));
+    }
+
+    pub fn is_pressed(&self, key: Keyboard) -> bool {
+        let usage = u8::from(key);
+        self.usages[(usage / 32) as usize] & (1 << (usage % 32)) != 0
+    }
+
+    pub fn press_modifiers(&mut self, modifiers: Modifiers) {
+        modifiers.keys().for_each(|key| self.press(key));
+    }
+
+    pub fn release_modifiers(&mut self, modifiers: Modifiers) {
+        modifiers.keys().for_each(|key| self.release(key));
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        let first = u8::from(Keyboard::LeftControl);
+        Modifiers((self.usages[(first / 32) as usize] >> (first % 32)) as u8)
+    }
+
+    /// Adds every key held in another report to this one
+    pub fn merge(&mut self, other: &KeyboardReport) {
+        for (usages, other) in self.usages.iter_mut().zip(other.usages) {
+            *usages |= other;
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Keyboard> + '_ {
+        self.usages.iter().enumerate().flat_map(|(word, &bits)| {
+            let mut bits = bits;
+            core::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+
+                let bit = bits.trailing_zeros();
+                // Clear the lowest set bit
+                bits &= bits - 1;
+
+                Some(Keyboard::from((word as u32 * 32 + bit) as u8))
+            })
+        })
+    }
+
+    /// The first six keys held besides the modifiers, the way a boot keyboard reports them, or
+    /// ErrorRollOver in every slot if there are more
+    pub fn boot_keys(&self) -> [Keyboard; 6] {
+        let mut boot_keys = [Keyboard::NoEventIndicated; 6];
+        let mut keys = self
+            .keys()
+            .filter(|&key| u8::from(key) < u8::from(Keyboard::LeftControl));
+
+        for (slot, key) in boot_keys.iter_mut().zip(&mut keys) {
+            *slot = key;
+        }
+
+        if keys.next().is_some() {
+            boot_keys = [Keyboard::ErrorRollOver; 6];
+        }
+        boot_keys
+    }
+
+    /// The 8 byte report a host that selected the boot protocol expects
+    pub fn to_boot_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[0] = self.modifiers().0;
+
+        for (byte, key) in bytes[2..].iter_mut().zip(self.boot_keys()) {
+            *byte = key.into();
+        }
+        bytes
+    }
+
+    /// The 25 byte report of the crate's NKRO keyboard descriptor: the boot report, followed by a
+    /// bit for each usage up to 0x87
+    pub fn to_nkro_bytes(self) -> [u8; 25] {
+        let mut bytes = [0; 25];
+        bytes[..8].copy_from_slice(&self.to_boot_bytes());
+
+        for key in self.keys() {
+            let usage = u8::from(key) as usize;
+            // The modifiers are only in the first byte
+            if let Some(byte) = bytes[8..].get_mut(usage / 8) {
+                *byte |= 1 << (usage % 8);
+            }
+        }
+        bytes
+    }
+
+    /// Only the modifiers and the keys a boot keyboard would report
+    pub fn with_six_keys(self) -> KeyboardReport {
+        let mut report = KeyboardReport::new();
+        report.press_modifiers(self.modifiers());
+        self.boot_keys()
+            .into_iter()
+            .for_each(|key| report.press(key));
+        report
+    }
+}
+
+/// Buttons held and relative movement since the last report
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    pub const fn new() -> Self {
+        MouseReport {
+            buttons: 0,
+            x: 0,
+            y: 0,
+            wheel: 0,
+            pan: 0,
+        }
+    }
+}
+
+impl From<MouseReport> for WheelMouseReport {
+    fn from(report: MouseReport) -> Self {
+        WheelMouseReport {
+            buttons: report.buttons,
+            x: report.x,
+            y: report.y,
+            vertical_wheel: report.wheel,
+            horizontal_wheel: report.pan,
+        }
+    }
+}
+
+/// A usage on the consumer page. Kept as a raw code since the crate's page is missing some, like
+/// the display brightness controls.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ConsumerUsage(u16);
+
+impl ConsumerUsage {
+    pub const PLAY_PAUSE: ConsumerUsage = ConsumerUsage::new(Consumer::PlayPause);
+    pub const NEXT_TRACK: ConsumerUsage = ConsumerUsage::new(Consumer::ScanNextTrack);
+    pub const PREVIOUS_TRACK: ConsumerUsage = ConsumerUsage::new(Consumer::ScanPreviousTrack);
+    pub const MUTE: ConsumerUsage = ConsumerUsage::new(Consumer::Mute);
+    pub const VOLUME_UP: ConsumerUsage = ConsumerUsage::new(Consumer::VolumeIncrement);
+    pub const VOLUME_DOWN: ConsumerUsage = ConsumerUsage::new(Consumer::VolumeDecrement);
+    pub const BRIGHTNESS_UP: ConsumerUsage = ConsumerUsage(0x6F);
+    pub const BRIGHTNESS_DOWN: ConsumerUsage = ConsumerUsage(0x70);
+    pub const CALCULATOR: ConsumerUsage = ConsumerUsage::new(Consumer::ALCalculator);
+    pub const BROWSER: ConsumerUsage = ConsumerUsage::new(Consumer::ALInternetBrowser);
+
+    pub const fn new(usage: Consumer) -> Self {
+        ConsumerUsage(usage as u16)
+    }
+}
+
+/// The consumer usages currently held down, as many as fit in a report
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct ConsumerReport {
+    usages: [u16; 4],
+}
+
+impl ConsumerReport {
+    pub const fn new() -> Self {
+        ConsumerReport { usages: [0; 4] }
+    }
+
+    /// Usages pressed while the report is full are dropped
+    pub fn press(&mut self, usage: ConsumerUsage) {
+        if self.usages.contains(&usage.0) {
+            return;
+        }
+
+        if let Some(slot) = self.usages.iter_mut().find(|slot| **slot == 0) {
+            *slot = usage.0;
+        }
+    }
+
+    pub fn release(&mut self, usage: ConsumerUsage) {
+        for slot in self.usages.iter_mut().filter(|slot| **slot == usage.0) {
+            *slot = 0;
+        }
+    }
+
+    /// Laid out for the crate's multiple code consumer control report descriptor
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        for (chunk, usage) in bytes.chunks_exact_mut(2).zip(self.usages) {
+            chunk.copy_from_slice(&usage.to_le_bytes());
+        }
+
+        bytes
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SystemUsage {
+    PowerDown,
+    Sleep,
+    WakeUp,
+}
+
+/// The system controls currently held down, a bit each in the order of `SystemUsage`
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct SystemReport(u8);
+
+impl SystemReport {
+    pub const fn new() -> Self {
+        SystemReport(0)
+    }
+
+    pub fn press(&mut self, control: SystemUsage) {
+        self.0 |= 1 << control as u8;
+    }
+
+    pub fn release(&mut self, control: SystemUsage) {
+        self.0 &= !(1 << control as u8);
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(keys: &[Keyboard]) -> KeyboardReport {
+        let mut report = KeyboardReport::new();
+        keys.iter().for_each(|&key| report.press(key));
+        report
+    }
+
+    #[test]
+    fn nkro_reports_start_with_the_boot_report() {
+        let report = report(&[Keyboard::LeftShift, Keyboard::A, Keyboard::Keyboard0]);
+        let bytes = report.to_nkro_bytes();
+
+        assert_eq!(bytes[..8], [0x02, 0, 0x04, 0x27, 0, 0, 0, 0]);
+        assert_eq!(bytes[..8], report.to_boot_bytes());
+        // A is usage 0x04 and 0 is usage 0x27, with nothing for the Shift
+        let mut bitmap = [0; 17];
+        bitmap[0] = 1 << 4;
+        bitmap[4] = 1 << 7;
+        assert_eq!(bytes[8..], bitmap);
+    }
+
+    #[test]
+    fn six_key_reports_roll_over_past_the_sixth_key() {
+        let six = [
+            Keyboard::A,
+            Keyboard::B,
+            Keyboard::C,
+            Keyboard::D,
+            Keyboard::E,
+            Keyboard::F,
+        ];
+        let mut keys = vec![Keyboard::LeftControl];
+        keys.extend(six);
+        assert!(report(&keys).with_six_keys() == report(&keys));
+
+        keys.push(Keyboard::G);
+        assert!(
+            report(&keys).with_six_keys()
+                == report(&[Keyboard::LeftControl, Keyboard::ErrorRollOver])
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::common::fixed_point_div;
 use crate::constants::NUMBER_OF_LEDS;
 use crate::hal::{RGBData, RGBEnable};
+use crate::layout::{self, KEY_UNIT};
 use cortex_m::singleton;
 use embedded_hal::digital::{OutputPin, StatefulOutputPin};
 use rp2040_hal::dma::single_buffer::Transfer;
@@ -349,6 +350,20 @@ impl RGBBufferManager {
         self.buffer.fill(color.as_u32());
     }
 
+    pub fn set(&mut self, index: usize, color: Color) {
+        self.buffer[index] = color.as_u32();
+    }
+
+    /// Scales every LED down, leaving them as they are at full brightness
+    pub fn dim(&mut self, brightness: u8) {
+        for led in self.buffer.iter_mut() {
+            let channels = led
+                .to_ne_bytes()
+                .map(|channel| (channel as u16 * brightness as u16 / u8::MAX as u16) as u8);
+            *led = u32::from_ne_bytes(channels);
+        }
+    }
+
     pub fn create() -> Self {
         let buffer = singleton!(: [u32; NUMBER_OF_LEDS] = [0; NUMBER_OF_LEDS]).unwrap();
 
@@ -356,6 +371,62 @@ impl RGBBufferManager {
     }
 }
 
+/// The effects that can be picked from VIA, numbered the way it lists them
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LightingEffect {
+    Off,
+    Solid,
+    RainbowWave,
+}
+
+impl LightingEffect {
+    pub const fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn from_id(id: u8) -> Option<LightingEffect> {
+        match id {
+            0 => Some(LightingEffect::Off),
+            1 => Some(LightingEffect::Solid),
+            2 => Some(LightingEffect::RainbowWave),
+            _ => None,
+        }
+    }
+}
+
+/// The lighting as changed from VIA
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LightingSettings {
+    pub brightness: u8,
+    pub effect: LightingEffect,
+    /// Kept for VIA to show, though the effects run at a fixed speed
+    pub speed: u8,
+    pub hue: u8,
+    pub saturation: u8,
+}
+
+impl LightingSettings {
+    pub const DEFAULT: LightingSettings = LightingSettings {
+        brightness: u8::MAX,
+        effect: LightingEffect::RainbowWave,
+        speed: u8::MAX / 2,
+        hue: 0,
+        saturation: u8::MAX,
+    };
+
+    // As dim as the rainbow wave, before the brightness is applied
+    const SOLID_LIGHTNESS: u8 = 0x0F;
+
+    /// The colour of the solid effect
+    pub const fn color(&self) -> Color {
+        Color::hsl(
+            self.hue as u16 * 257,
+            self.saturation,
+            Self::SOLID_LIGHTNESS,
+        )
+    }
+}
+
 pub trait RGBEffect {
     fn apply_effect(&mut self, buffer: &mut RGBBufferManager);
 }
@@ -414,15 +485,13 @@ impl<const HSUB: u16, const S: u8, const L: u8, const STEP: u16> RGBEffect
 {
     fn apply_effect(&mut self, buffer: &mut RGBBufferManager) {
         let unit_movement: u16 = u16::MAX / (16 * HSUB);
+        // Across the board from left to right, following where the keys actually are
         buffer.fill_with_iter(
-            (0..=14)
-                .chain((0..=14).rev())
-                .chain(0..=13)
-                .chain((0..=13).rev())
-                .chain(0..=9)
-                .map(|x| self.current_hue.wrapping_add(x * unit_movement))
-                .map(|h| Color::hsl(h, S, L))
-                .cycle(),
+            (0..NUMBER_OF_LEDS)
+                .map(|led| layout::led_key(led).map_or(0, |key| key.centre().0))
+                .map(|x| (x as u32 * unit_movement as u32 / KEY_UNIT as u32) as u16)
+                .map(|offset| self.current_hue.wrapping_add(offset))
+                .map(|h| Color::hsl(h, S, L)),
         );
 
         self.current_hue = self.current_hue.wrapping_add(STEP);
@@ -0,0 +1,197 @@
+//! Keeps what's changed from VIA and Vial, and the modes picked with keys, in flash, so it's still
+//! there after the keyboard is unplugged. Saving waits until VIA has gone quiet for a moment, and
+//! only what has changed is written.
+//!
+//! The settings are saved with the version of their layout, and brought up to date by the
+//! migrations when newer firmware starts. Anything that can't be read is forgotten, leaving the
+//! keyboard as it was built.
+
+mod migration;
+
+use crate::constants::{SETTINGS_SAVE_DELAY, VIA_MACRO_BUFFER_SIZE};
+use crate::keymap::{KeyMap, KeymapEngine, UnicodeMode};
+use crate::rgb::{LightingEffect, LightingSettings};
+use crate::storage::{Flash, Store};
+use crate::via::keycodes;
+use rp2040_hal::timer::Instant;
+
+// Keys in the store
+const LIGHTING: u8 = 0x00;
+/// Left off after the last macro, so the buffer can change size
+const MACROS: u8 = 0x01;
+const UNICODE_MODE: u8 = 0x02;
+const NKRO: u8 = 0x03;
+/// Followed by a key for each of the other layers that can be remapped
+const KEYMAP_LAYER_0: u8 = 0x10;
+/// The version of the layout everything else was saved in
+const VERSION: u8 = 0xFE;
+
+/// Saved in place of a key that hasn't been remapped
+const NOT_REMAPPED: u16 = u16::MAX;
+
+/// Big enough for the macros or a layer of the keymap
+const BUFFER_SIZE: usize = 512;
+
+pub struct Settings<F: Flash> {
+    store: Store<F>,
+    /// When VIA last sent a command or a key changed a mode, while that hasn't been saved
+    changed_at: Option<Instant>,
+    /// What's saved of the settings changed from keys rather than VIA, to notice them changing
+    unicode_mode: UnicodeMode,
+    nkro: bool,
+}
+
+impl<F: Flash> Settings<F> {
+    pub fn new(flash: F) -> Self {
+        let mut store = Store::new(flash);
+        migration::upgrade(&mut store);
+
+        Settings {
+            store,
+            changed_at: None,
+            unicode_mode: UnicodeMode::DEFAULT,
+            nkro: true,
+        }
+    }
+
+    /// Puts back whatever was saved. Anything missing, or that doesn't look right, is left as it
+    /// was built.
+    pub fn load<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+        &mut self,
+        keymap: &mut KeymapEngine<NROW, NCOL, M>,
+        lighting: &mut LightingSettings,
+    ) {
+        let mut buffer = [0; BUFFER_SIZE];
+
+        let mut bytes = [0; 5];
+        let saved = self.store.get(LIGHTING, &mut bytes) == Some(bytes.len());
+        if let Some(effect) = LightingEffect::from_id(bytes[1]).filter(|_| saved) {
+            *lighting = LightingSettings {
+                brightness: bytes[0],
+                effect,
+                speed: bytes[2],
+                hue: bytes[3],
+                saturation: bytes[4],
+            };
+        }
+
+        let macros = &mut buffer[..VIA_MACRO_BUFFER_SIZE];
+        if let Some(length) = self
+            .store
+            .get(MACROS, macros)
+            .filter(|&length| length <= macros.len())
+        {
+            keymap.macro_buffer_mut().write(0, &macros[..length]);
+        }
+
+        let mut mode = [0];
+        if let Some(mode) = self
+            .store
+            .get(UNICODE_MODE, &mut mode)
+            .and_then(|_| UnicodeMode::from_id(mode[0]))
+        {
+            keymap.set_unicode_mode(mode);
+        }
+        self.unicode_mode = keymap.unicode_mode();
+
+        let mut nkro = [0];
+        if self.store.get(NKRO, &mut nkro) == Some(nkro.len()) {
+            keymap.set_nkro(nkro[0] != 0);
+        }
+        self.nkro = keymap.is_nkro();
+
+        for layer in 0..keymap.dynamic_layer_count() {
+            let keys = &mut buffer[..layer_size::<NROW, NCOL>()];
+            if self.store.get(KEYMAP_LAYER_0 + layer as u8, keys) != Some(keys.len()) {
+                continue;
+            }
+
+            for (index, bytes) in keys.chunks_exact(2).enumerate() {
+                let (row, col) = ((index / NCOL) as u8, (index % NCOL) as u8);
+                let keycode = u16::from_le_bytes([bytes[0], bytes[1]]);
+
+                if let Some(action) = keycodes::action(keycode).filter(|_| keycode != NOT_REMAPPED)
+                {
+                    keymap.set_keymap_action(layer, row, col, action);
+                }
+            }
+        }
+    }
+
+    /// Notes that VIA has sent a command, which might have changed something
+    pub fn changed(&mut self, now: Instant) {
+        self.changed_at = Some(now);
+    }
+
+    /// Saves everything once VIA has stopped changing things for a while, or a key has changed
+    /// something that's saved
+    pub fn save_when_settled<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+        &mut self,
+        keymap: &KeymapEngine<NROW, NCOL, M>,
+        lighting: &LightingSettings,
+        now: Instant,
+    ) {
+        let modes_changed =
+            keymap.unicode_mode() != self.unicode_mode || keymap.is_nkro() != self.nkro;
+        if self.changed_at.is_none() && modes_changed {
+            self.changed_at = Some(now);
+        }
+
+        if !self
+            .changed_at
+            .is_some_and(|changed_at| now >= changed_at + SETTINGS_SAVE_DELAY)
+        {
+            return;
+        }
+        self.changed_at = None;
+
+        // Everything saved fits in a sector several times over, so there's always room
+        let mut buffer = [0; BUFFER_SIZE];
+
+        self.store.set(
+            LIGHTING,
+            &[
+                lighting.brightness,
+                lighting.effect.id(),
+                lighting.speed,
+                lighting.hue,
+                lighting.saturation,
+            ],
+        );
+
+        self.unicode_mode = keymap.unicode_mode();
+        self.store.set(UNICODE_MODE, &[self.unicode_mode.id()]);
+        self.nkro = keymap.is_nkro();
+        self.store.set(NKRO, &[self.nkro as u8]);
+
+        let macros = &mut buffer[..VIA_MACRO_BUFFER_SIZE];
+        keymap.macro_buffer().read(0, macros);
+        self.store.set(MACROS, without_trailing_zeros(macros));
+
+        for layer in 0..keymap.dynamic_layer_count() {
+            let keys = &mut buffer[..layer_size::<NROW, NCOL>()];
+            for (index, bytes) in keys.chunks_exact_mut(2).enumerate() {
+                let (row, col) = ((index / NCOL) as u8, (index % NCOL) as u8);
+                let keycode = keymap
+                    .remapped_action(layer, row, col)
+                    .map_or(NOT_REMAPPED, keycodes::keycode);
+                bytes.copy_from_slice(&keycode.to_le_bytes());
+            }
+
+            self.store.set(KEYMAP_LAYER_0 + layer as u8, keys);
+        }
+    }
+}
+
+fn without_trailing_zeros(bytes: &[u8]) -> &[u8] {
+    let length = bytes
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    &bytes[..length]
+}
+
+/// The bytes taken by the keycodes of one layer, which has to fit in the buffer
+const fn layer_size<const NROW: usize, const NCOL: usize>() -> usize {
+    NROW * NCOL * 2
+}
@@ -0,0 +1,212 @@
+//! Brings settings saved by older firmware up to the layout in use now. Each step spells out the
+//! old layout it reads, so it keeps working however the rest of the settings change.
+
+use crate::settings::{without_trailing_zeros, LIGHTING, MACROS, VERSION};
+use crate::storage::{Flash, Store};
+
+/// The layout read and written by settings.rs
+const CURRENT_VERSION: u8 = 2;
+
+/// Upgrades the saved settings one version at a time. If they're from a version this firmware
+/// doesn't know, or one of the steps can't read them, they're all forgotten.
+pub fn upgrade<F: Flash>(store: &mut Store<F>) {
+    let Some(mut version) = saved_version(store) else {
+        store.set(VERSION, &[CURRENT_VERSION]);
+        return;
+    };
+
+    while version < CURRENT_VERSION {
+        let upgraded = match version {
+            1 => version_1_to_2(store),
+            _ => false,
+        };
+        if !upgraded {
+            break;
+        }
+
+        // Saved after every step, so losing power part way through starts again from that step,
+        // which is why each can be run twice
+        version += 1;
+        store.set(VERSION, &[version]);
+    }
+
+    if version != CURRENT_VERSION {
+        store.clear();
+        store.set(VERSION, &[CURRENT_VERSION]);
+    }
+}
+
+/// The version the settings were saved in, or nothing if there aren't any
+fn saved_version<F: Flash>(store: &Store<F>) -> Option<u8> {
+    let mut version = [0];
+    match store.get(VERSION, &mut version) {
+        Some(1) => Some(version[0]),
+        // Never a version that exists, so the settings are thrown away
+        Some(_) => Some(0),
+        // Version 1 didn't save its version, but always saved the lighting with everything else
+        None => store.get(LIGHTING, &mut []).map(|_| 1),
+    }
+}
+
+/// Version 1 saved the whole macro buffer, 512 bytes at the time. Version 2 leaves off the zeros
+/// after the last macro.
+fn version_1_to_2<F: Flash>(store: &mut Store<F>) -> bool {
+    const MACRO_BUFFER_SIZE: usize = 512;
+
+    let mut macros = [0; MACRO_BUFFER_SIZE];
+    match store.get(MACROS, &mut macros) {
+        Some(length) if length <= MACRO_BUFFER_SIZE => {
+            store.set(MACROS, without_trailing_zeros(&macros[..length]))
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::KEYMAP_LAYER_0;
+    use crate::storage::RamFlash;
+
+    const V1_LIGHTING: [u8; 5] = [0x80, 2, 0x40, 0x10, 0xFF];
+
+    fn get(store: &Store<RamFlash>, key: u8) -> Option<Vec<u8>> {
+        let mut data = [0; 1024];
+        store
+            .get(key, &mut data)
+            .map(|length| data[..length].to_vec())
+    }
+
+    /// Settings as the first firmware to save them left them, with no version
+    fn version_1(macros: &[u8]) -> RamFlash {
+        let flash = RamFlash::new();
+        let mut store = Store::new(flash.clone());
+        store.set(LIGHTING, &V1_LIGHTING);
+        store.set(MACROS, macros);
+        store.set(KEYMAP_LAYER_0, &[0x04; 150]);
+        flash
+    }
+
+    fn v1_macros() -> [u8; 512] {
+        let mut macros = [0; 512];
+        macros[..6].copy_from_slice(b"hi\0yo\0");
+        macros
+    }
+
+    fn upgraded(flash: &RamFlash) -> Store<RamFlash> {
+        let mut store = Store::new(flash.clone());
+        upgrade(&mut store);
+        store
+    }
+
+    #[test]
+    fn nothing_saved_starts_at_the_current_version() {
+        let store = upgraded(&RamFlash::new());
+        assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+        assert_eq!(get(&store, LIGHTING), None);
+    }
+
+    #[test]
+    fn version_1_is_upgraded() {
+        let store = upgraded(&version_1(&v1_macros()));
+
+        assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+        assert_eq!(get(&store, MACROS).as_deref(), Some(&b"hi\0yo"[..]));
+        assert_eq!(get(&store, LIGHTING), Some(V1_LIGHTING.to_vec()));
+        assert_eq!(get(&store, KEYMAP_LAYER_0), Some(vec![0x04; 150]));
+    }
+
+    #[test]
+    fn the_current_version_is_left_alone() {
+        let flash = version_1(&v1_macros());
+        upgraded(&flash);
+
+        let mut store = Store::new(flash.clone());
+        store.set(MACROS, b"changed since");
+        let store = upgraded(&flash);
+
+        assert_eq!(get(&store, MACROS).as_deref(), Some(&b"changed since"[..]));
+        assert_eq!(get(&store, LIGHTING), Some(V1_LIGHTING.to_vec()));
+    }
+
+    #[test]
+    fn settings_that_cant_be_read_are_forgotten() {
+        // Longer than version 1 ever saved the macros
+        let store = upgraded(&version_1(&[b'x'; 600]));
+
+        assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+        assert_eq!(get(&store, LIGHTING), None);
+        assert_eq!(get(&store, MACROS), None);
+        assert_eq!(get(&store, KEYMAP_LAYER_0), None);
+    }
+
+    #[test]
+    fn a_corrupt_record_is_lost_along_with_those_after_it() {
+        let flash = version_1(&v1_macros());
+        // Into the macros, after the store's header and the lighting, each with a 7 byte header
+        flash.corrupt(12 + 7 + V1_LIGHTING.len() + 7 + 1);
+        let store = upgraded(&flash);
+
+        assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+        assert_eq!(get(&store, LIGHTING), Some(V1_LIGHTING.to_vec()));
+        assert_eq!(get(&store, MACROS), None);
+        assert_eq!(get(&store, KEYMAP_LAYER_0), None);
+    }
+
+    #[test]
+    fn a_version_from_newer_firmware_is_forgotten() {
+        for version in [CURRENT_VERSION + 1, 0xFF] {
+            let flash = version_1(&v1_macros());
+            Store::new(flash.clone()).set(VERSION, &[version]);
+            let store = upgraded(&flash);
+
+            assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+            assert_eq!(get(&store, LIGHTING), None);
+            assert_eq!(get(&store, MACROS), None);
+        }
+    }
+
+    #[test]
+    fn a_version_that_isnt_a_byte_is_forgotten() {
+        let flash = version_1(&v1_macros());
+        Store::new(flash.clone()).set(VERSION, &[1, 0]);
+        let store = upgraded(&flash);
+
+        assert_eq!(get(&store, VERSION), Some(vec![CURRENT_VERSION]));
+        assert_eq!(get(&store, LIGHTING), None);
+    }
+
+    #[test]
+    fn an_upgrade_cut_short_finishes_the_next_time() {
+        for cut in 0.. {
+            let flash = version_1(&v1_macros());
+
+            flash.lose_power_after(cut);
+            upgraded(&flash);
+            let finished = !flash.lost_power();
+            flash.restore_power();
+
+            let store = upgraded(&flash);
+            assert_eq!(
+                get(&store, VERSION),
+                Some(vec![CURRENT_VERSION]),
+                "cut after {cut}"
+            );
+            assert_eq!(
+                get(&store, MACROS).as_deref(),
+                Some(&b"hi\0yo"[..]),
+                "cut after {cut}"
+            );
+            assert_eq!(
+                get(&store, LIGHTING),
+                Some(V1_LIGHTING.to_vec()),
+                "cut after {cut}"
+            );
+
+            if finished {
+                break;
+            }
+        }
+    }
+}
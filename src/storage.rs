@@ -0,0 +1,532 @@
+//! A small key-value store in flash, standing in for the EEPROM QMK keyboards save their settings
+//! to.
+//!
+//! Values are appended to one sector at a time as records checked by a CRC, so changing one never
+//! rewrites what's already there. Once a sector fills up, the latest value of every key is copied
+//! into the next sector round, spreading the erases over all of them. A sector only takes over
+//! once its header is written, after everything has been copied, so losing power part way through
+//! leaves the old sector in use, and a record cut short fails its CRC and is ignored.
+
+mod flash;
+#[cfg(test)]
+mod ram;
+
+pub use flash::QspiFlash;
+#[cfg(test)]
+pub use ram::RamFlash;
+
+/// Flash that can be erased a sector at a time and written a byte at a time, with offsets from
+/// the start of the part set aside for the store
+pub trait Flash {
+    /// The size of the whole store
+    const SIZE: usize;
+    const SECTOR_SIZE: usize;
+
+    fn read(&self, offset: usize, data: &mut [u8]);
+
+    /// Sets every byte in the sector starting at `offset` back to `0xFF`
+    fn erase(&mut self, offset: usize);
+
+    /// Writes to bytes that have been erased since they were last written
+    fn program(&mut self, offset: usize, data: &[u8]);
+}
+
+/// Marks a sector as belonging to the store
+const MAGIC: u32 = 0x5641_4C53;
+/// The magic, the sector's sequence number and a CRC of both
+const SECTOR_HEADER_SIZE: usize = 12;
+/// The key, length and CRC of a record, before its value
+const RECORD_HEADER_SIZE: usize = 7;
+/// Erased flash reads back as this, so it can't be a key
+const FREE: u8 = 0xFF;
+
+/// How much is read from flash at once to check or copy a record
+const CHUNK_SIZE: usize = 256;
+
+// Kept small, as one is held for every key while moving to the next sector
+#[derive(Copy, Clone)]
+struct Record {
+    key: u8,
+    /// From the start of the sector, to the record's header
+    offset: u16,
+    length: u16,
+}
+
+impl Record {
+    const fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    const fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    const fn size(&self) -> usize {
+        RECORD_HEADER_SIZE + self.length()
+    }
+}
+
+/// What comes next in a sector
+enum Slot {
+    Record(Record),
+    Free,
+    /// Either garbage or a record only partly written
+    Corrupt,
+}
+
+pub struct Store<F: Flash> {
+    flash: F,
+    /// The sector in use
+    sector: usize,
+    sequence: u32,
+    /// Where the next record goes in the sector
+    end: usize,
+}
+
+impl<F: Flash> Store<F> {
+    const SECTORS: usize = F::SIZE / F::SECTOR_SIZE;
+
+    /// Finds the sector in use, or starts the store over in the first sector if there's none
+    pub fn new(flash: F) -> Self {
+        assert!(Self::SECTORS >= 2, "the store needs a sector to copy into");
+        assert!(
+            F::SECTOR_SIZE <= 1 << u16::BITS,
+            "offsets must fit in a record"
+        );
+
+        let mut store = Store {
+            flash,
+            sector: 0,
+            sequence: 0,
+            end: SECTOR_HEADER_SIZE,
+        };
+
+        let latest = (0..Self::SECTORS)
+            .filter_map(|sector| Some((store.sector_sequence(sector)?, sector)))
+            .max();
+
+        match latest {
+            Some((sequence, sector)) => {
+                store.sector = sector;
+                store.sequence = sequence;
+                store.end = store.find_end();
+            }
+            None => {
+                store.flash.erase(0);
+                store.write_sector_header(0, 0);
+            }
+        }
+
+        store
+    }
+
+    /// Copies out the latest value of a key, returning its length, which may be more than fits in
+    /// `data`
+    pub fn get(&self, key: u8, data: &mut [u8]) -> Option<usize> {
+        let record = self.find(key)?;
+
+        let length = record.length().min(data.len());
+        self.flash.read(
+            self.sector_start() + record.offset() + RECORD_HEADER_SIZE,
+            &mut data[..length],
+        );
+
+        Some(record.length())
+    }
+
+    /// Saves a value for a key, returning whether there was room for it. Nothing is written if
+    /// the value hasn't changed.
+    pub fn set(&mut self, key: u8, data: &[u8]) -> bool {
+        assert_ne!(key, FREE, "0xFF marks the free space in a sector");
+
+        if self
+            .find(key)
+            .is_some_and(|record| self.holds(record, data))
+        {
+            return true;
+        }
+
+        if self.end + RECORD_HEADER_SIZE + data.len() <= F::SECTOR_SIZE {
+            self.write_record(self.sector_start() + self.end, key, data);
+            self.end += RECORD_HEADER_SIZE + data.len();
+            true
+        } else {
+            self.move_to_next_sector(key, data)
+        }
+    }
+
+    /// Copies the latest value of every other key into the next sector along with the new value,
+    /// then switches to it
+    fn move_to_next_sector(&mut self, key: u8, data: &[u8]) -> bool {
+        let mut latest = [None; FREE as usize];
+        for record in self.records() {
+            latest[record.key as usize] = Some(record);
+        }
+        latest[key as usize] = None;
+
+        let kept = latest.iter().flatten().map(Record::size).sum::<usize>();
+        if SECTOR_HEADER_SIZE + kept + RECORD_HEADER_SIZE + data.len() > F::SECTOR_SIZE {
+            return false;
+        }
+
+        let next = (self.sector + 1) % Self::SECTORS;
+        let next_start = next * F::SECTOR_SIZE;
+        self.flash.erase(next_start);
+
+        let mut end = SECTOR_HEADER_SIZE;
+        let mut chunk = [0; CHUNK_SIZE];
+        for record in latest.into_iter().flatten() {
+            // Records don't depend on where they are, so they're copied as they are
+            let from = self.sector_start() + record.offset();
+            for copied in (0..record.size()).step_by(CHUNK_SIZE) {
+                let length = (record.size() - copied).min(CHUNK_SIZE);
+                self.flash.read(from + copied, &mut chunk[..length]);
+                self.flash
+                    .program(next_start + end + copied, &chunk[..length]);
+            }
+            end += record.size();
+        }
+
+        self.write_record(next_start + end, key, data);
+        end += RECORD_HEADER_SIZE + data.len();
+
+        self.switch_to(next, end);
+        true
+    }
+
+    /// Forgets every key, by moving on to an empty sector
+    pub fn clear(&mut self) {
+        let next = (self.sector + 1) % Self::SECTORS;
+        self.flash.erase(next * F::SECTOR_SIZE);
+        self.switch_to(next, SECTOR_HEADER_SIZE);
+    }
+
+    /// Starts using a sector that has had everything copied into it
+    fn switch_to(&mut self, sector: usize, end: usize) {
+        // Written last, as the sector is only used once it has a header
+        self.write_sector_header(sector, self.sequence + 1);
+        self.sector = sector;
+        self.sequence += 1;
+        self.end = end;
+    }
+
+    fn sector_start(&self) -> usize {
+        self.sector * F::SECTOR_SIZE
+    }
+
+    /// The sequence number of a sector, if it has been taken over by the store
+    fn sector_sequence(&self, sector: usize) -> Option<u32> {
+        let mut header = [0; SECTOR_HEADER_SIZE];
+        self.flash.read(sector * F::SECTOR_SIZE, &mut header);
+
+        let [magic, sequence, crc] =
+            [0, 4, 8].map(|at| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()));
+        (magic == MAGIC && crc == crc32(&header[..8])).then_some(sequence)
+    }
+
+    fn write_sector_header(&mut self, sector: usize, sequence: u32) {
+        let mut header = [0; SECTOR_HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc32(&header[..8]);
+        header[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        self.flash.program(sector * F::SECTOR_SIZE, &header);
+    }
+
+    fn write_record(&mut self, offset: usize, key: u8, data: &[u8]) {
+        let length = (data.len() as u16).to_le_bytes();
+        let crc = crc32_update(crc32_update(!0, &[key, length[0], length[1]]), data);
+
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[0] = key;
+        header[1..3].copy_from_slice(&length);
+        header[3..7].copy_from_slice(&(!crc).to_le_bytes());
+
+        // The header goes first, so a value cut short fails its CRC rather than leaving bytes
+        // that look free but aren't
+        self.flash.program(offset, &header);
+        self.flash.program(offset + RECORD_HEADER_SIZE, data);
+    }
+
+    /// The record starting at an offset into the sector in use
+    fn slot(&self, offset: usize) -> Slot {
+        if offset + RECORD_HEADER_SIZE > F::SECTOR_SIZE {
+            return Slot::Free;
+        }
+
+        let start = self.sector_start() + offset;
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.flash.read(start, &mut header);
+
+        if header.iter().all(|&byte| byte == FREE) {
+            return Slot::Free;
+        }
+
+        let key = header[0];
+        let length = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let crc = u32::from_le_bytes(header[3..7].try_into().unwrap());
+        if key == FREE || offset + RECORD_HEADER_SIZE + length > F::SECTOR_SIZE {
+            return Slot::Corrupt;
+        }
+
+        let mut computed = crc32_update(!0, &header[..3]);
+        let mut chunk = [0; CHUNK_SIZE];
+        for read in (0..length).step_by(CHUNK_SIZE) {
+            let size = (length - read).min(CHUNK_SIZE);
+            self.flash
+                .read(start + RECORD_HEADER_SIZE + read, &mut chunk[..size]);
+            computed = crc32_update(computed, &chunk[..size]);
+        }
+
+        if !computed == crc {
+            Slot::Record(Record {
+                key,
+                offset: offset as u16,
+                length: length as u16,
+            })
+        } else {
+            Slot::Corrupt
+        }
+    }
+
+    /// Every whole record in the sector in use, oldest first
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let mut offset = SECTOR_HEADER_SIZE;
+        core::iter::from_fn(move || match self.slot(offset) {
+            Slot::Record(record) => {
+                offset += record.size();
+                Some(record)
+            }
+            Slot::Free | Slot::Corrupt => None,
+        })
+    }
+
+    /// Where the next record can go. Nothing more is written to a sector after a corrupt record,
+    /// as its length can't be trusted to say where it ends.
+    fn find_end(&self) -> usize {
+        let mut offset = SECTOR_HEADER_SIZE;
+        loop {
+            match self.slot(offset) {
+                Slot::Record(record) => offset += record.size(),
+                Slot::Free => return offset,
+                Slot::Corrupt => return F::SECTOR_SIZE,
+            }
+        }
+    }
+
+    /// The latest record for a key
+    fn find(&self, key: u8) -> Option<Record> {
+        self.records().filter(|record| record.key == key).last()
+    }
+
+    /// Whether a record holds exactly this value
+    fn holds(&self, record: Record, data: &[u8]) -> bool {
+        let start = self.sector_start() + record.offset() + RECORD_HEADER_SIZE;
+        let mut chunk = [0; CHUNK_SIZE];
+
+        record.length() == data.len()
+            && data
+                .chunks(CHUNK_SIZE)
+                .enumerate()
+                .all(|(index, expected)| {
+                    let read = &mut chunk[..expected.len()];
+                    self.flash.read(start + index * CHUNK_SIZE, read);
+                    read == expected
+                })
+    }
+}
+
+/// The table for the usual CRC-32, reflected with the polynomial `0xEDB88320`
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Carries on a CRC, started from `!0` and inverted once every byte is in
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(store: &Store<RamFlash>, key: u8) -> Option<Vec<u8>> {
+        let mut data = [0; 256];
+        store
+            .get(key, &mut data)
+            .map(|length| data[..length].to_vec())
+    }
+
+    /// A store with its first sector nearly full, so the next value for key 1 moves it on
+    fn nearly_full() -> (RamFlash, Store<RamFlash>) {
+        let flash = RamFlash::new();
+        let mut store = Store::new(flash.clone());
+        store.set(0, b"kept");
+
+        for value in 0.. {
+            if store.end + RECORD_HEADER_SIZE + 100 > RamFlash::SECTOR_SIZE {
+                break;
+            }
+            store.set(1, &[value; 100]);
+        }
+
+        (flash, store)
+    }
+
+    #[test]
+    fn values_are_there_after_starting_again() {
+        let flash = RamFlash::new();
+        let mut store = Store::new(flash.clone());
+        store.set(0, b"first");
+        store.set(1, b"other");
+        store.set(0, b"second");
+        store.set(2, b"");
+
+        let store = Store::new(flash);
+        assert_eq!(get(&store, 0).as_deref(), Some(&b"second"[..]));
+        assert_eq!(get(&store, 1).as_deref(), Some(&b"other"[..]));
+        assert_eq!(get(&store, 2).as_deref(), Some(&b""[..]));
+        assert_eq!(get(&store, 3), None);
+    }
+
+    #[test]
+    fn values_failing_their_crc_are_ignored() {
+        let flash = RamFlash::new();
+        let mut store = Store::new(flash.clone());
+        store.set(0, b"old");
+        store.set(0, b"new");
+
+        let second = SECTOR_HEADER_SIZE + RECORD_HEADER_SIZE + 3;
+        flash.corrupt(second + RECORD_HEADER_SIZE + 1);
+
+        let mut store = Store::new(flash.clone());
+        assert_eq!(get(&store, 0).as_deref(), Some(&b"old"[..]));
+
+        // Nothing more goes after the corrupt record, so this moves on to the next sector
+        assert!(store.set(1, b"after"));
+        let store = Store::new(flash);
+        assert_eq!(get(&store, 0).as_deref(), Some(&b"old"[..]));
+        assert_eq!(get(&store, 1).as_deref(), Some(&b"after"[..]));
+    }
+
+    #[test]
+    fn sectors_are_erased_in_turn() {
+        let flash = RamFlash::new();
+        let mut store = Store::new(flash.clone());
+        store.set(0, b"kept");
+
+        for value in 0..2000 {
+            store.set(1, &(value as u32).to_le_bytes().repeat(25));
+        }
+
+        let erases = flash.erases();
+        let (least, most) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(
+            *least > 0 && most - least <= 1,
+            "erased unevenly: {erases:?}"
+        );
+
+        let store = Store::new(flash);
+        assert_eq!(get(&store, 0).as_deref(), Some(&b"kept"[..]));
+        assert_eq!(get(&store, 1), Some(1999u32.to_le_bytes().repeat(25)));
+    }
+
+    #[test]
+    fn losing_power_part_way_through_a_value_keeps_the_old_one() {
+        for cut in 0.. {
+            let flash = RamFlash::new();
+            let mut store = Store::new(flash.clone());
+            store.set(0, b"old value");
+            store.set(1, b"other");
+
+            flash.lose_power_after(cut);
+            store.set(0, b"new value");
+            let finished = !flash.lost_power();
+            flash.restore_power();
+
+            let mut store = Store::new(flash.clone());
+            let expected: &[u8] = if finished { b"new value" } else { b"old value" };
+            assert_eq!(get(&store, 0).as_deref(), Some(expected), "cut after {cut}");
+            assert_eq!(
+                get(&store, 1).as_deref(),
+                Some(&b"other"[..]),
+                "cut after {cut}"
+            );
+
+            store.set(0, b"newer");
+            let store = Store::new(flash);
+            assert_eq!(
+                get(&store, 0).as_deref(),
+                Some(&b"newer"[..]),
+                "cut after {cut}"
+            );
+
+            if finished {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn losing_power_part_way_through_moving_sectors_keeps_the_old_one() {
+        for cut in 0.. {
+            let (flash, mut store) = nearly_full();
+            let old = get(&store, 1);
+
+            flash.lose_power_after(cut);
+            store.set(1, &[0xAA; 100]);
+            let finished = !flash.lost_power();
+            flash.restore_power();
+
+            let mut store = Store::new(flash.clone());
+            let expected = if finished { Some(vec![0xAA; 100]) } else { old };
+            assert_eq!(get(&store, 1), expected, "cut after {cut}");
+            assert_eq!(
+                get(&store, 0).as_deref(),
+                Some(&b"kept"[..]),
+                "cut after {cut}"
+            );
+
+            store.set(1, b"newer");
+            let store = Store::new(flash);
+            assert_eq!(
+                get(&store, 1).as_deref(),
+                Some(&b"newer"[..]),
+                "cut after {cut}"
+            );
+            assert_eq!(
+                get(&store, 0).as_deref(),
+                Some(&b"kept"[..]),
+                "cut after {cut}"
+            );
+
+            if finished {
+                assert_eq!(store.sector, 1);
+                break;
+            }
+        }
+    }
+}
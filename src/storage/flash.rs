@@ -0,0 +1,141 @@
+use crate::constants::{STORAGE_OFFSET, STORAGE_SIZE};
+use crate::storage::Flash;
+use core::sync::atomic::{compiler_fence, Ordering};
+use rp2040_hal::rom_data;
+
+/// Where the flash can be read from, through the XIP cache
+const XIP_BASE: usize = 0x1000_0000;
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: usize = 4096;
+// Erases 64K at once where it can, though the store only ever erases a sector
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_COMMAND: u8 = 0xD8;
+
+/// The part of the QSPI flash set aside for the store in `memory.x`.
+///
+/// The program runs from the same flash, so nothing can be read from it while it's being written.
+/// Writes are carried out from RAM with interrupts off, through the bootrom's functions, and
+/// reading from flash is set up again afterwards by the copy of boot2 kept here.
+pub struct QspiFlash {
+    boot2: [u32; PAGE_SIZE / 4],
+}
+
+impl QspiFlash {
+    pub fn new() -> Self {
+        let mut boot2 = [0; PAGE_SIZE / 4];
+        // Boot2 is the first page of flash
+        unsafe {
+            core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len())
+        };
+
+        QspiFlash { boot2 }
+    }
+
+    fn run(&mut self, operation: Operation) {
+        let functions = RomFunctions::find();
+        // Called as Thumb code
+        let boot2 = self.boot2.as_ptr() as usize + 1;
+
+        cortex_m::interrupt::free(|_| {
+            compiler_fence(Ordering::SeqCst);
+            unsafe { run_from_ram(&functions, boot2, &operation) };
+            compiler_fence(Ordering::SeqCst);
+        });
+    }
+}
+
+impl Flash for QspiFlash {
+    const SIZE: usize = STORAGE_SIZE;
+    const SECTOR_SIZE: usize = SECTOR_SIZE;
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        assert!(offset + data.len() <= STORAGE_SIZE);
+
+        let address = (XIP_BASE + STORAGE_OFFSET + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(address, data.as_mut_ptr(), data.len()) };
+    }
+
+    fn erase(&mut self, offset: usize) {
+        assert!(offset.is_multiple_of(SECTOR_SIZE) && offset + SECTOR_SIZE <= STORAGE_SIZE);
+
+        self.run(Operation::Erase {
+            address: (STORAGE_OFFSET + offset) as u32,
+        });
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= STORAGE_SIZE);
+
+        // Only whole pages can be programmed. Writing 0xFF leaves a byte as it was, so the rest of
+        // each page is filled with that.
+        let mut written = 0;
+        while written < data.len() {
+            let at = offset + written;
+            let page_start = at - at % PAGE_SIZE;
+            let length = (PAGE_SIZE - at % PAGE_SIZE).min(data.len() - written);
+
+            let mut page = [0xFF; PAGE_SIZE];
+            page[at % PAGE_SIZE..][..length].copy_from_slice(&data[written..written + length]);
+            self.run(Operation::Program {
+                address: (STORAGE_OFFSET + page_start) as u32,
+                page: &page,
+            });
+
+            written += length;
+        }
+    }
+}
+
+enum Operation<'a> {
+    Erase {
+        address: u32,
+    },
+    Program {
+        address: u32,
+        page: &'a [u8; PAGE_SIZE],
+    },
+}
+
+/// The bootrom functions, looked up beforehand as the lookup itself runs from flash
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl RomFunctions {
+    fn find() -> Self {
+        RomFunctions {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+/// Kept in RAM by the linker, so it keeps running while the flash can't be read
+#[inline(never)]
+#[unsafe(link_section = ".data.ram_func")]
+unsafe fn run_from_ram(functions: &RomFunctions, boot2: usize, operation: &Operation) {
+    unsafe {
+        (functions.connect_internal_flash)();
+        (functions.flash_exit_xip)();
+
+        match *operation {
+            Operation::Erase { address } => {
+                (functions.flash_range_erase)(address, SECTOR_SIZE, BLOCK_SIZE, BLOCK_ERASE_COMMAND)
+            }
+            Operation::Program { address, page } => {
+                (functions.flash_range_program)(address, page.as_ptr(), PAGE_SIZE)
+            }
+        }
+
+        (functions.flash_flush_cache)();
+        let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as *const ());
+        boot2();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::storage::Flash;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Flash kept in RAM for the tests, which can have its power cut part way through a write.
+///
+/// Clones share the same memory, so a store can be started again on what another left behind, as
+/// happens when the keyboard is plugged back in.
+#[derive(Clone)]
+pub struct RamFlash {
+    memory: Rc<RefCell<Vec<u8>>>,
+    erases: Rc<RefCell<Vec<usize>>>,
+    /// How many more bytes can be written or sectors erased before the power goes
+    power_left: Rc<Cell<Option<usize>>>,
+    /// Whether anything has gone unwritten for want of power
+    lost_power: Rc<Cell<bool>>,
+}
+
+impl RamFlash {
+    pub fn new() -> Self {
+        RamFlash {
+            memory: Rc::new(RefCell::new(vec![0xFF; Self::SIZE])),
+            erases: Rc::new(RefCell::new(vec![0; Self::SIZE / Self::SECTOR_SIZE])),
+            power_left: Rc::new(Cell::new(None)),
+            lost_power: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Stops anything more being written once this many bytes have been, counting an erase as one
+    pub fn lose_power_after(&self, writes: usize) {
+        self.power_left.set(Some(writes));
+    }
+
+    /// Whether the power went before everything was written
+    pub fn lost_power(&self) -> bool {
+        self.lost_power.get()
+    }
+
+    pub fn restore_power(&self) {
+        self.power_left.set(None);
+        self.lost_power.set(false);
+    }
+
+    /// How many times each sector has been erased
+    pub fn erases(&self) -> Vec<usize> {
+        self.erases.borrow().clone()
+    }
+
+    /// Clears some bits of a byte, as if it had worn out
+    pub fn corrupt(&self, offset: usize) {
+        self.memory.borrow_mut()[offset] &= 0x5A;
+    }
+
+    fn use_power(&self) -> bool {
+        match self.power_left.get() {
+            Some(0) => {
+                self.lost_power.set(true);
+                false
+            }
+            Some(left) => {
+                self.power_left.set(Some(left - 1));
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl Flash for RamFlash {
+    const SIZE: usize = 4 * 4096;
+    const SECTOR_SIZE: usize = 4096;
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        data.copy_from_slice(&self.memory.borrow()[offset..offset + data.len()]);
+    }
+
+    fn erase(&mut self, offset: usize) {
+        assert_eq!(
+            offset % Self::SECTOR_SIZE,
+            0,
+            "erased part way into a sector"
+        );
+
+        if self.use_power() {
+            self.memory.borrow_mut()[offset..offset + Self::SECTOR_SIZE].fill(0xFF);
+            self.erases.borrow_mut()[offset / Self::SECTOR_SIZE] += 1;
+        }
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) {
+        for (at, &byte) in (offset..).zip(data) {
+            if !self.use_power() {
+                return;
+            }
+
+            // Programming can only clear bits, which is what erasing is for
+            let mut memory = self.memory.borrow_mut();
+            assert_eq!(
+                memory[at] & byte,
+                byte,
+                "programmed over byte {at} without erasing it"
+            );
+            memory[at] &= byte;
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use rp2040_hal::fugit::ExtU32;
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::usb_class::prelude::{
+    DeviceClass, InBytes8, Interface, InterfaceBuilder, InterfaceConfig, OutNone, ReportSingle,
+    UsbAllocatable, UsbHidError,
+};
+
+use crate::report::SystemReport;
+
+/// Generic Desktop system control, a bit each for Power Down, Sleep and Wake Up
+#[rustfmt::skip]
+pub const SYSTEM_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop),
+    0x09, 0x80, // Usage (System Control),
+    0xA1, 0x01, // Collection (Application),
+    0x19, 0x81, //     Usage Minimum (System Power Down),
+    0x29, 0x83, //     Usage Maximum (System Wake Up),
+    0x15, 0x00, //     Logical Minimum (0),
+    0x25, 0x01, //     Logical Maximum (1),
+    0x75, 0x01, //     Report Size (1),
+    0x95, 0x03, //     Report Count (3),
+    0x81, 0x02, //     Input (Data, Variable, Absolute),
+    0x95, 0x05, //     Report Count (5),
+    0x81, 0x01, //     Input (Constant), padding to a byte
+    0xC0,       // End Collection
+];
+
+/// The crate has no system control device, so this is one in the same shape as its own
+pub struct SystemControl<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes8, OutNone, ReportSingle>,
+}
+
+impl<B: UsbBus> SystemControl<'_, B> {
+    pub fn write_report(&mut self, report: &SystemReport) -> usb_device::Result<usize> {
+        self.interface.write_report(&[report.bits()])
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for SystemControl<'a, B> {
+    type I = Interface<'a, B, InBytes8, OutNone, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct SystemControlConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes8, OutNone, ReportSingle>,
+}
+
+impl Default for SystemControlConfig<'_> {
+    fn default() -> Self {
+        SystemControlConfig {
+            interface: InterfaceBuilder::new(SYSTEM_CONTROL_REPORT_DESCRIPTOR)
+                .unwrap()
+                .description("System Control")
+                .in_endpoint(50.millis())
+                .unwrap()
+                .without_out_endpoint()
+                .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for SystemControlConfig<'a> {
+    type Allocated = SystemControl<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        SystemControl {
+            interface: Interface::new(usb_alloc, self.interface),
+        }
+    }
+}
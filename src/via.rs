@@ -0,0 +1,299 @@
+//! The VIA protocol, which lets the VIA app remap keys, edit macros and change the lighting while
+//! the keyboard is running. Every command is a raw HID report, answered by sending it back with
+//! the results written over it.
+
+pub mod keycodes;
+
+use crate::constants::{VIA_MACRO_BUFFER_SIZE, VIA_MACRO_COUNT};
+use crate::keymap::{KeyMap, KeymapEngine};
+use crate::raw_hid::RAW_HID_REPORT_SIZE;
+use crate::rgb::{LightingEffect, LightingSettings};
+use crate::vial::{self, Vial};
+use rp2040_hal::rom_data::reset_to_usb_boot;
+use rp2040_hal::timer::Instant;
+
+const PROTOCOL_VERSION: u16 = 0x000C;
+
+// Commands, in the first byte of a report
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const GET_KEYBOARD_VALUE: u8 = 0x02;
+const SET_KEYBOARD_VALUE: u8 = 0x03;
+const GET_KEYCODE: u8 = 0x04;
+const SET_KEYCODE: u8 = 0x05;
+const RESET_KEYMAP: u8 = 0x06;
+const SET_LIGHTING_VALUE: u8 = 0x07;
+const GET_LIGHTING_VALUE: u8 = 0x08;
+const SAVE_LIGHTING: u8 = 0x09;
+const RESET_EVERYTHING: u8 = 0x0A;
+const JUMP_TO_BOOTLOADER: u8 = 0x0B;
+const GET_MACRO_COUNT: u8 = 0x0C;
+const GET_MACRO_BUFFER_SIZE: u8 = 0x0D;
+const GET_MACRO_BUFFER: u8 = 0x0E;
+const SET_MACRO_BUFFER: u8 = 0x0F;
+const RESET_MACROS: u8 = 0x10;
+const GET_LAYER_COUNT: u8 = 0x11;
+const GET_KEYMAP_BUFFER: u8 = 0x12;
+const SET_KEYMAP_BUFFER: u8 = 0x13;
+/// Followed by one of Vial's own commands
+const VIAL_PREFIX: u8 = 0xFE;
+/// Sent back in place of a command that isn't supported
+const UNHANDLED: u8 = 0xFF;
+
+// Keyboard values
+const UPTIME: u8 = 0x01;
+const LAYOUT_OPTIONS: u8 = 0x02;
+const SWITCH_MATRIX_STATE: u8 = 0x03;
+const FIRMWARE_VERSION: u8 = 0x04;
+
+// The lighting is shown in VIA as an RGB matrix
+const RGB_MATRIX_CHANNEL: u8 = 3;
+const BRIGHTNESS: u8 = 1;
+const EFFECT: u8 = 2;
+const EFFECT_SPEED: u8 = 3;
+const COLOR: u8 = 4;
+
+/// The most bytes of a buffer read or written in one report, after the command, offset and size
+const MAX_CHUNK: usize = RAW_HID_REPORT_SIZE - 4;
+
+/// Carries out the command in a report from the host, leaving the reply in its place
+pub fn process<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    report: &mut [u8; RAW_HID_REPORT_SIZE],
+    keymap: &mut KeymapEngine<NROW, NCOL, M>,
+    matrix: &[[bool; NCOL]; NROW],
+    lighting: &mut LightingSettings,
+    vial: &mut Vial,
+    now: Instant,
+) {
+    match report[0] {
+        GET_PROTOCOL_VERSION => report[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match report[1] {
+            UPTIME => {
+                let uptime = now.duration_since_epoch().to_millis() as u32;
+                report[2..6].copy_from_slice(&uptime.to_be_bytes());
+            }
+            LAYOUT_OPTIONS | FIRMWARE_VERSION => report[2..6].fill(0),
+            SWITCH_MATRIX_STATE => {
+                // Two bytes a row from the requested one, as many as fit. The switches themselves,
+                // so keys held back by combos or tap-holds still show up in VIA's tester.
+                let offset = report[2] as usize;
+                for (row, bytes) in (offset..NROW).zip(report[3..].chunks_exact_mut(2)) {
+                    let pressed = (0..NCOL)
+                        .filter(|&col| matrix[row][col])
+                        .fold(0u16, |pressed, col| pressed | 1 << col);
+                    bytes.copy_from_slice(&pressed.to_be_bytes());
+                }
+            }
+            _ => report[0] = UNHANDLED,
+        },
+        // Layout options and identifying the device don't do anything on this board
+        SET_KEYBOARD_VALUE => {}
+        GET_KEYCODE => {
+            let [layer, row, col] = [report[1], report[2], report[3]];
+            let keycode = keymap
+                .keymap_action(layer as usize, row, col)
+                .map_or(0, keycodes::keycode);
+            report[4..6].copy_from_slice(&keycode.to_be_bytes());
+        }
+        SET_KEYCODE => {
+            let [layer, row, col] = [report[1], report[2], report[3]];
+            let keycode = u16::from_be_bytes([report[4], report[5]]);
+            // Keycodes this keyboard can't do are left as they were
+            if let Some(action) = keycodes::action(keycode) {
+                keymap.set_keymap_action(layer as usize, row, col, action);
+            }
+        }
+        RESET_KEYMAP => keymap.reset_keymap(),
+        SET_LIGHTING_VALUE | GET_LIGHTING_VALUE | SAVE_LIGHTING
+            if report[1] != RGB_MATRIX_CHANNEL =>
+        {
+            report[0] = UNHANDLED
+        }
+        SET_LIGHTING_VALUE => {
+            let value = &report[3..];
+            match report[2] {
+                BRIGHTNESS => lighting.brightness = value[0],
+                EFFECT => {
+                    if let Some(effect) = LightingEffect::from_id(value[0]) {
+                        lighting.effect = effect;
+                    }
+                }
+                EFFECT_SPEED => lighting.speed = value[0],
+                COLOR => (lighting.hue, lighting.saturation) = (value[0], value[1]),
+                _ => report[0] = UNHANDLED,
+            }
+        }
+        GET_LIGHTING_VALUE => {
+            let (request, value) = report.split_at_mut(3);
+            match request[2] {
+                BRIGHTNESS => value[0] = lighting.brightness,
+                EFFECT => value[0] = lighting.effect.id(),
+                EFFECT_SPEED => value[0] = lighting.speed,
+                COLOR => value[..2].copy_from_slice(&[lighting.hue, lighting.saturation]),
+                _ => request[0] = UNHANDLED,
+            }
+        }
+        // Everything is saved once VIA stops sending commands, so there's nothing more to do
+        SAVE_LIGHTING => {}
+        RESET_EVERYTHING => {
+            keymap.reset_keymap();
+            keymap.reset_features();
+            // Locked away like the rest of the macro commands
+            if vial.is_unlocked() {
+                keymap.macro_buffer_mut().reset();
+            }
+            *lighting = LightingSettings::DEFAULT;
+        }
+        // Macros can type anything, and the bootloader takes new firmware, so a program on the
+        // host can't do either until the keyboard is unlocked from Vial. Refused rather than
+        // ignored, so VIA doesn't think the macros were saved.
+        JUMP_TO_BOOTLOADER | SET_MACRO_BUFFER | RESET_MACROS if !vial.is_unlocked() => {
+            report[0] = UNHANDLED
+        }
+        JUMP_TO_BOOTLOADER => reset_to_usb_boot(0, 0),
+        GET_MACRO_COUNT => report[1] = VIA_MACRO_COUNT,
+        GET_MACRO_BUFFER_SIZE => {
+            report[1..3].copy_from_slice(&(VIA_MACRO_BUFFER_SIZE as u16).to_be_bytes())
+        }
+        GET_MACRO_BUFFER => {
+            let (offset, size) = chunk(report);
+            keymap.macro_buffer().read(offset, &mut report[4..4 + size]);
+        }
+        SET_MACRO_BUFFER => {
+            let (offset, size) = chunk(report);
+            keymap
+                .macro_buffer_mut()
+                .write(offset, &report[4..4 + size]);
+        }
+        RESET_MACROS => keymap.macro_buffer_mut().reset(),
+        GET_LAYER_COUNT => report[1] = keymap.dynamic_layer_count() as u8,
+        GET_KEYMAP_BUFFER => {
+            let (offset, size) = chunk(report);
+            for (at, byte) in (offset..).zip(&mut report[4..4 + size]) {
+                let (layer, row, col) = keymap_position::<NROW, NCOL>(at / 2);
+                let keycode = keymap
+                    .keymap_action(layer, row, col)
+                    .map_or(0, keycodes::keycode);
+                *byte = keycode.to_be_bytes()[at % 2];
+            }
+        }
+        SET_KEYMAP_BUFFER => {
+            let (offset, size) = chunk(report);
+            let data = &report[4..4 + size];
+
+            for index in offset / 2..(offset + size).div_ceil(2) {
+                let (layer, row, col) = keymap_position::<NROW, NCOL>(index);
+                let Some(action) = keymap.keymap_action(layer, row, col) else {
+                    break;
+                };
+
+                // Offsets are in bytes, so a keycode can be half written
+                let mut bytes = keycodes::keycode(action).to_be_bytes();
+                for (at, byte) in (index * 2..).zip(&mut bytes) {
+                    if let Some(&new) = at.checked_sub(offset).and_then(|at| data.get(at)) {
+                        *byte = new;
+                    }
+                }
+
+                if let Some(action) = keycodes::action(u16::from_be_bytes(bytes)) {
+                    keymap.set_keymap_action(layer, row, col, action);
+                }
+            }
+        }
+        VIAL_PREFIX => vial::process(report, keymap, matrix, vial, now),
+        _ => report[0] = UNHANDLED,
+    }
+}
+
+/// The byte offset and length of a buffer read or write, clamped to fit in the report
+fn chunk(report: &[u8; RAW_HID_REPORT_SIZE]) -> (usize, usize) {
+    let offset = u16::from_be_bytes([report[1], report[2]]) as usize;
+    let size = (report[3] as usize).min(MAX_CHUNK);
+    (offset, size)
+}
+
+/// Where the keycode at an index in the keymap buffer is, which VIA lays out layer by layer, then
+/// row by row
+fn keymap_position<const NROW: usize, const NCOL: usize>(index: usize) -> (usize, u8, u8) {
+    let layer = index / (NROW * NCOL);
+    let row = index / NCOL % NROW;
+    let col = index % NCOL;
+    (layer, row as u8, col as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::BasicKeymap;
+
+    #[test]
+    fn macros_are_refused_until_vial_is_unlocked() {
+        let mut keymap = KeymapEngine::<5, 15, BasicKeymap>::new();
+        let mut lighting = LightingSettings::DEFAULT;
+        let mut vial = Vial::new();
+
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        report[..6].copy_from_slice(&[SET_MACRO_BUFFER, 0, 0, 2, b'h', b'i']);
+        process(
+            &mut report,
+            &mut keymap,
+            &[[false; 15]; 5],
+            &mut lighting,
+            &mut vial,
+            Instant::from_ticks(0),
+        );
+
+        assert_eq!(report[0], UNHANDLED);
+        let mut written = [0; 2];
+        keymap.macro_buffer().read(0, &mut written);
+        assert_eq!(written, [0, 0]);
+    }
+
+    #[test]
+    fn the_matrix_tester_sees_the_switches() {
+        let mut keymap = KeymapEngine::<5, 15, BasicKeymap>::new();
+        let mut lighting = LightingSettings::DEFAULT;
+        let mut vial = Vial::new();
+
+        // Held back by the keymap, as the start of a combo and a home row mod
+        let mut matrix = [[false; 15]; 5];
+        matrix[2][7] = true;
+        matrix[2][1] = true;
+
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        report[..3].copy_from_slice(&[GET_KEYBOARD_VALUE, SWITCH_MATRIX_STATE, 1]);
+        process(
+            &mut report,
+            &mut keymap,
+            &matrix,
+            &mut lighting,
+            &mut vial,
+            Instant::from_ticks(0),
+        );
+
+        // From row 1 on
+        assert_eq!(report[3..11], [0, 0, 0, 0b1000_0010, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn resetting_everything_leaves_the_macros_until_vial_is_unlocked() {
+        let mut keymap = KeymapEngine::<5, 15, BasicKeymap>::new();
+        keymap.macro_buffer_mut().write(0, b"hi");
+        let mut lighting = LightingSettings::DEFAULT;
+        let mut vial = Vial::new();
+
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        report[0] = RESET_EVERYTHING;
+        process(
+            &mut report,
+            &mut keymap,
+            &[[false; 15]; 5],
+            &mut lighting,
+            &mut vial,
+            Instant::from_ticks(0),
+        );
+
+        let mut written = [0; 2];
+        keymap.macro_buffer().read(0, &mut written);
+        assert_eq!(&written, b"hi");
+    }
+}
@@ -0,0 +1,238 @@
+//! Converts between actions and the 16 bit keycodes VIA shows and edits, which are QMK's
+
+use crate::keymap::{Action, MouseButton, MouseDirection, UnicodeMode};
+use crate::report::{ConsumerUsage, Modifiers, SystemUsage};
+use usbd_human_interface_device::page::Keyboard;
+
+const NO: u16 = 0x0000;
+const TRANSPARENT: u16 = 0x0001;
+const MOD_TAP: u16 = 0x2000;
+const LAYER_TAP: u16 = 0x4000;
+const MOMENTARY: u16 = 0x5220;
+const DEFAULT_LAYER: u16 = 0x5240;
+const TOGGLE_LAYER: u16 = 0x5260;
+const ONE_SHOT_LAYER: u16 = 0x5280;
+const ONE_SHOT_MODS: u16 = 0x52A0;
+const TAP_DANCE: u16 = 0x5700;
+const NKRO_TOGGLE: u16 = 0x7013;
+const MACRO: u16 = 0x7700;
+const AUTO_SHIFT_TOGGLE: u16 = 0x7C15;
+const GRAVE_ESCAPE: u16 = 0x7C16;
+const LEFT_SHIFT_PARENTHESIS: u16 = 0x7C1A;
+const RIGHT_SHIFT_PARENTHESIS: u16 = 0x7C1B;
+const UNICODE_MODE_MACOS: u16 = 0x7C32;
+const UNICODE_MODE_LINUX: u16 = 0x7C33;
+const UNICODE_MODE_WINCOMPOSE: u16 = 0x7C36;
+const RECORD_MACRO: u16 = 0x7C53;
+const STOP_RECORDING: u16 = 0x7C55;
+const PLAY_RECORDING: u16 = 0x7C56;
+const LEADER: u16 = 0x7C58;
+const CAPS_WORD: u16 = 0x7C73;
+const LAYER_LOCK: u16 = 0x7C7B;
+/// Shown for actions VIA has no keycode for, so they can be told apart from an empty key
+pub const USER: u16 = 0x7E40;
+const UNICODE: u16 = 0x8000;
+
+const LEFT_CONTROL: u8 = 0xE0;
+const RIGHT_GUI: u8 = 0xE7;
+
+// The media, system and mouse keys QMK keeps among the basic keycodes
+const CONSUMER_KEYS: [(u8, ConsumerUsage); 9] = [
+    (0xA8, ConsumerUsage::MUTE),
+    (0xA9, ConsumerUsage::VOLUME_UP),
+    (0xAA, ConsumerUsage::VOLUME_DOWN),
+    (0xAB, ConsumerUsage::NEXT_TRACK),
+    (0xAC, ConsumerUsage::PREVIOUS_TRACK),
+    (0xAE, ConsumerUsage::PLAY_PAUSE),
+    (0xB2, ConsumerUsage::CALCULATOR),
+    (0xBD, ConsumerUsage::BRIGHTNESS_UP),
+    (0xBE, ConsumerUsage::BRIGHTNESS_DOWN),
+];
+const SYSTEM_KEYS: [(u8, SystemUsage); 3] = [
+    (0xA5, SystemUsage::PowerDown),
+    (0xA6, SystemUsage::Sleep),
+    (0xA7, SystemUsage::WakeUp),
+];
+const MOUSE_MOVE_KEYS: [(u8, MouseDirection); 4] = [
+    (0xCD, MouseDirection::Up),
+    (0xCE, MouseDirection::Down),
+    (0xCF, MouseDirection::Left),
+    (0xD0, MouseDirection::Right),
+];
+const MOUSE_BUTTON_KEYS: [(u8, MouseButton); 5] = [
+    (0xD1, MouseButton::Left),
+    (0xD2, MouseButton::Right),
+    (0xD3, MouseButton::Middle),
+    (0xD4, MouseButton::Back),
+    (0xD5, MouseButton::Forward),
+];
+const MOUSE_WHEEL_KEYS: [(u8, MouseDirection); 4] = [
+    (0xD9, MouseDirection::Up),
+    (0xDA, MouseDirection::Down),
+    (0xDB, MouseDirection::Left),
+    (0xDC, MouseDirection::Right),
+];
+
+/// The keycode for an action, or `USER` if VIA has none
+pub fn keycode(action: Action) -> u16 {
+    to_keycode(action).unwrap_or(USER)
+}
+
+fn to_keycode(action: Action) -> Option<u16> {
+    let keycode = match action {
+        Action::NoOp => NO,
+        Action::Transparent => TRANSPARENT,
+        Action::Key(key) => basic(key)? as u16,
+        Action::Modifiers(modifiers) => {
+            // A single modifier is a key of its own
+            match (LEFT_CONTROL..=RIGHT_GUI).find(|&code| modifier(code) == modifiers) {
+                Some(code) => code as u16,
+                None => (mods5(modifiers)? as u16) << 8,
+            }
+        }
+        Action::ModifiedKey(modifiers, key) => (mods5(modifiers)? as u16) << 8 | basic(key)? as u16,
+        Action::ModTap(modifiers, key) => {
+            MOD_TAP | (mods5(modifiers)? as u16) << 8 | basic(key)? as u16
+        }
+        Action::LayerTap(layer, key) if layer < 16 => {
+            LAYER_TAP | (layer as u16) << 8 | basic(key)? as u16
+        }
+        Action::MomentaryLayer(layer) if layer < 32 => MOMENTARY | layer as u16,
+        Action::DefaultLayer(layer) if layer < 32 => DEFAULT_LAYER | layer as u16,
+        Action::ToggleLayer(layer) if layer < 32 => TOGGLE_LAYER | layer as u16,
+        Action::OneShotLayer(layer) if layer < 32 => ONE_SHOT_LAYER | layer as u16,
+        Action::OneShotModifiers(modifiers) => ONE_SHOT_MODS | mods5(modifiers)? as u16,
+        Action::TapDance(index) => TAP_DANCE | index as u16,
+        Action::ViaMacro(index) if index < 0x80 => MACRO | index as u16,
+        Action::ToggleNkro => NKRO_TOGGLE,
+        Action::ToggleAutoShift => AUTO_SHIFT_TOGGLE,
+        Action::GraveEscape => GRAVE_ESCAPE,
+        Action::SpaceCadet(Modifiers::LEFT_SHIFT, Keyboard::Keyboard9) => LEFT_SHIFT_PARENTHESIS,
+        Action::SpaceCadet(Modifiers::RIGHT_SHIFT, Keyboard::Keyboard0) => RIGHT_SHIFT_PARENTHESIS,
+        Action::SetUnicodeMode(UnicodeMode::MacOs) => UNICODE_MODE_MACOS,
+        Action::SetUnicodeMode(UnicodeMode::Linux) => UNICODE_MODE_LINUX,
+        Action::SetUnicodeMode(UnicodeMode::WinCompose) => UNICODE_MODE_WINCOMPOSE,
+        Action::RecordMacro(slot) if slot < 2 => RECORD_MACRO + slot as u16,
+        Action::StopRecording => STOP_RECORDING,
+        Action::PlayRecording(slot) if slot < 2 => PLAY_RECORDING + slot as u16,
+        Action::Leader => LEADER,
+        Action::CapsWord => CAPS_WORD,
+        Action::LayerLock => LAYER_LOCK,
+        Action::Unicode(character) if (character as u32) < 0x8000 => UNICODE | character as u16,
+        Action::Consumer(usage) => find_code(&CONSUMER_KEYS, usage)? as u16,
+        Action::System(usage) => find_code(&SYSTEM_KEYS, usage)? as u16,
+        Action::MouseMove(direction) => find_code(&MOUSE_MOVE_KEYS, direction)? as u16,
+        Action::MouseButton(button) => find_code(&MOUSE_BUTTON_KEYS, button)? as u16,
+        Action::MouseWheel(direction) => find_code(&MOUSE_WHEEL_KEYS, direction)? as u16,
+        _ => return None,
+    };
+
+    Some(keycode)
+}
+
+/// The action for a keycode, if it's one this keyboard can do
+pub fn action(keycode: u16) -> Option<Action> {
+    let [high, low] = keycode.to_be_bytes();
+
+    let action = match keycode {
+        NO => Action::NoOp,
+        TRANSPARENT => Action::Transparent,
+        0x0004..=0x00FF => basic_action(low)?,
+        0x0100..=0x1FFF if low == 0 => Action::Modifiers(from_mods5(high)),
+        0x0100..=0x1FFF => Action::ModifiedKey(from_mods5(high), key(low)?),
+        0x2000..=0x3FFF => Action::ModTap(from_mods5(high & 0x1F), key(low)?),
+        0x4000..=0x4FFF => Action::LayerTap(high & 0x0F, key(low)?),
+        0x5220..=0x523F => Action::MomentaryLayer(low & 0x1F),
+        0x5240..=0x525F => Action::DefaultLayer(low & 0x1F),
+        0x5260..=0x527F => Action::ToggleLayer(low & 0x1F),
+        0x5280..=0x529F => Action::OneShotLayer(low & 0x1F),
+        0x52A0..=0x52BF => Action::OneShotModifiers(from_mods5(low & 0x1F)),
+        0x5700..=0x57FF => Action::TapDance(low),
+        0x7700..=0x777F => Action::ViaMacro(low),
+        NKRO_TOGGLE => Action::ToggleNkro,
+        AUTO_SHIFT_TOGGLE => Action::ToggleAutoShift,
+        GRAVE_ESCAPE => Action::GraveEscape,
+        LEFT_SHIFT_PARENTHESIS => Action::SpaceCadet(Modifiers::LEFT_SHIFT, Keyboard::Keyboard9),
+        RIGHT_SHIFT_PARENTHESIS => Action::SpaceCadet(Modifiers::RIGHT_SHIFT, Keyboard::Keyboard0),
+        UNICODE_MODE_MACOS => Action::SetUnicodeMode(UnicodeMode::MacOs),
+        UNICODE_MODE_LINUX => Action::SetUnicodeMode(UnicodeMode::Linux),
+        UNICODE_MODE_WINCOMPOSE => Action::SetUnicodeMode(UnicodeMode::WinCompose),
+        0x7C53..=0x7C54 => Action::RecordMacro((keycode - RECORD_MACRO) as u8),
+        STOP_RECORDING => Action::StopRecording,
+        0x7C56..=0x7C57 => Action::PlayRecording((keycode - PLAY_RECORDING) as u8),
+        LEADER => Action::Leader,
+        CAPS_WORD => Action::CapsWord,
+        LAYER_LOCK => Action::LayerLock,
+        0x8000..=0xFFFF => Action::Unicode(char::from_u32((keycode & 0x7FFF) as u32)?),
+        _ => return None,
+    };
+
+    Some(action)
+}
+
+fn basic_action(code: u8) -> Option<Action> {
+    let action = if let Some(usage) = find_value(&CONSUMER_KEYS, code) {
+        Action::Consumer(usage)
+    } else if let Some(usage) = find_value(&SYSTEM_KEYS, code) {
+        Action::System(usage)
+    } else if let Some(direction) = find_value(&MOUSE_MOVE_KEYS, code) {
+        Action::MouseMove(direction)
+    } else if let Some(button) = find_value(&MOUSE_BUTTON_KEYS, code) {
+        Action::MouseButton(button)
+    } else if let Some(direction) = find_value(&MOUSE_WHEEL_KEYS, code) {
+        Action::MouseWheel(direction)
+    } else if (LEFT_CONTROL..=RIGHT_GUI).contains(&code) {
+        Action::Modifiers(modifier(code))
+    } else {
+        Action::Key(key(code)?)
+    };
+
+    Some(action)
+}
+
+/// The keyboard usage for one of QMK's basic keycodes, which share their numbering up to Help
+fn key(code: u8) -> Option<Keyboard> {
+    (0x04..=0xA4).contains(&code).then(|| Keyboard::from(code))
+}
+
+fn basic(key: Keyboard) -> Option<u8> {
+    let code = u8::from(key);
+    (0x04..=0xA4).contains(&code).then_some(code)
+}
+
+fn modifier(code: u8) -> Modifiers {
+    Modifiers::from_bits(1 << (code - LEFT_CONTROL))
+}
+
+/// QMK's five bit modifiers: Ctrl, Shift, Alt and GUI, then a bit for the right hand ones. Left
+/// and right hand modifiers can't be mixed.
+fn mods5(modifiers: Modifiers) -> Option<u8> {
+    let bits = modifiers.bits();
+    match (bits & 0x0F, bits >> 4) {
+        (left, 0) => Some(left),
+        (0, right) => Some(0x10 | right),
+        _ => None,
+    }
+}
+
+fn from_mods5(mods: u8) -> Modifiers {
+    if mods & 0x10 != 0 {
+        Modifiers::from_bits((mods & 0x0F) << 4)
+    } else {
+        Modifiers::from_bits(mods & 0x0F)
+    }
+}
+
+fn find_code<T: PartialEq>(table: &[(u8, T)], value: T) -> Option<u8> {
+    table
+        .iter()
+        .find(|(_, entry)| *entry == value)
+        .map(|&(code, _)| code)
+}
+
+fn find_value<T: Copy>(table: &[(u8, T)], code: u8) -> Option<T> {
+    table
+        .iter()
+        .find(|&&(entry, _)| entry == code)
+        .map(|&(_, value)| value)
+}
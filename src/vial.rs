@@ -0,0 +1,411 @@
+//! Vial's extensions to the VIA protocol, sent as VIA commands with their own prefix. They give
+//! Vial the keyboard's definition, so no JSON file has to be loaded, let it edit tap dances,
+//! combos and key overrides, and keep a few risky commands locked until the user holds down the
+//! unlock keys on the keyboard itself.
+
+use crate::constants::{
+    MAX_COMBOS, MAX_KEY_OVERRIDES, MAX_TAP_DANCES, TAPPING_TERM, VIAL_KEYBOARD_UID,
+    VIAL_UNLOCK_HOLD_TIME, VIAL_UNLOCK_KEYS,
+};
+use crate::keymap::{Action, Combo, KeyMap, KeyOverride, KeymapEngine, TapDance};
+use crate::raw_hid::RAW_HID_REPORT_SIZE;
+use crate::report::Modifiers;
+use crate::via::keycodes;
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+// The keyboard's definition, compressed by build.rs from layout.json
+include!(concat!(env!("OUT_DIR"), "/vial.rs"));
+
+/// The first version with QMK's current keycode numbering
+const PROTOCOL_VERSION: u32 = 6;
+
+// Commands, in the second byte of a report after VIA's command for Vial
+const GET_KEYBOARD_ID: u8 = 0x00;
+const GET_DEFINITION_SIZE: u8 = 0x01;
+const GET_DEFINITION: u8 = 0x02;
+const GET_UNLOCK_STATUS: u8 = 0x05;
+const UNLOCK_START: u8 = 0x06;
+const UNLOCK_POLL: u8 = 0x07;
+const LOCK: u8 = 0x08;
+const QMK_SETTINGS_QUERY: u8 = 0x09;
+const DYNAMIC_ENTRY_OP: u8 = 0x0D;
+
+// Operations on tap dances, combos and key overrides, in the third byte
+const GET_ENTRY_COUNTS: u8 = 0x00;
+const GET_TAP_DANCE: u8 = 0x01;
+const SET_TAP_DANCE: u8 = 0x02;
+const GET_COMBO: u8 = 0x03;
+const SET_COMBO: u8 = 0x04;
+const GET_KEY_OVERRIDE: u8 = 0x05;
+const SET_KEY_OVERRIDE: u8 = 0x06;
+
+const ENTRY_OK: u8 = 0x00;
+const ENTRY_ERROR: u8 = 0xFF;
+
+// Vial's key override options. Overrides here always act like this, whatever is set.
+const OVERRIDE_ON_TRIGGER_DOWN: u8 = 1 << 0;
+const OVERRIDE_ON_MODIFIER_DOWN: u8 = 1 << 1;
+const OVERRIDE_ANY_MODIFIER: u8 = 1 << 3;
+const OVERRIDE_ENABLED: u8 = 1 << 7;
+
+/// Vial's combos have at most this many keys
+const COMBO_KEYS: usize = 4;
+
+/// The progress Vial shows while the unlock keys are held counts down in these steps
+const UNLOCK_STEP: MicrosDurationU32 = MicrosDurationU32::millis(100);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Lock {
+    Locked,
+    /// Waiting for the unlock keys to be held, since the time they all went down
+    Unlocking(Option<Instant>),
+    Unlocked,
+}
+
+/// Whether the commands that could be abused by a program on the host are allowed, like jumping
+/// to the bootloader. They stay locked until the user proves they are at the keyboard.
+pub struct Vial {
+    lock: Lock,
+}
+
+impl Vial {
+    pub const fn new() -> Self {
+        Vial { lock: Lock::Locked }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.lock == Lock::Unlocked
+    }
+
+    /// How many steps are left of holding the unlock keys
+    fn unlock_countdown(&self, now: Instant) -> u8 {
+        let steps = |time: MicrosDurationU32| (time.ticks() / UNLOCK_STEP.ticks()) as u8;
+
+        match self.lock {
+            Lock::Unlocking(Some(since)) => {
+                let held = now
+                    .checked_duration_since(since)
+                    .map_or(0, |held| held.ticks());
+                let left = VIAL_UNLOCK_HOLD_TIME.ticks().saturating_sub(held as u32);
+                steps(MicrosDurationU32::micros(left))
+            }
+            _ => steps(VIAL_UNLOCK_HOLD_TIME),
+        }
+    }
+}
+
+/// Carries out a Vial command in a report from the host, leaving the reply in its place
+pub fn process<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    report: &mut [u8; RAW_HID_REPORT_SIZE],
+    keymap: &mut KeymapEngine<NROW, NCOL, M>,
+    matrix: &[[bool; NCOL]; NROW],
+    vial: &mut Vial,
+    now: Instant,
+) {
+    match report[1] {
+        GET_KEYBOARD_ID => {
+            report[0..4].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+            report[4..12].copy_from_slice(&VIAL_KEYBOARD_UID);
+            // No VialRGB
+            report[12] = 0;
+        }
+        GET_DEFINITION_SIZE => {
+            report[0..4].copy_from_slice(&(VIAL_DEFINITION.len() as u32).to_le_bytes())
+        }
+        GET_DEFINITION => {
+            let page = u16::from_le_bytes([report[2], report[3]]) as usize;
+            let start = (page * RAW_HID_REPORT_SIZE).min(VIAL_DEFINITION.len());
+            let end = (start + RAW_HID_REPORT_SIZE).min(VIAL_DEFINITION.len());
+            report[..end - start].copy_from_slice(&VIAL_DEFINITION[start..end]);
+        }
+        GET_UNLOCK_STATUS => {
+            report.fill(0xFF);
+            report[0] = vial.is_unlocked() as u8;
+            report[1] = matches!(vial.lock, Lock::Unlocking(_)) as u8;
+            for (bytes, (row, col)) in report[2..].chunks_exact_mut(2).zip(VIAL_UNLOCK_KEYS) {
+                bytes.copy_from_slice(&[row, col]);
+            }
+        }
+        UNLOCK_START => {
+            if !vial.is_unlocked() {
+                vial.lock = Lock::Unlocking(None);
+            }
+        }
+        UNLOCK_POLL => {
+            if let Lock::Unlocking(since) = vial.lock {
+                let holding = VIAL_UNLOCK_KEYS
+                    .iter()
+                    .all(|&(row, col)| matrix[row as usize][col as usize]);
+
+                // Letting go of any of the keys starts the wait over
+                vial.lock = match (holding, since) {
+                    (false, _) => Lock::Unlocking(None),
+                    (true, None) => Lock::Unlocking(Some(now)),
+                    (true, Some(since)) if now >= since + VIAL_UNLOCK_HOLD_TIME => Lock::Unlocked,
+                    (true, Some(since)) => Lock::Unlocking(Some(since)),
+                };
+            }
+
+            report[0] = vial.is_unlocked() as u8;
+            report[1] = matches!(vial.lock, Lock::Unlocking(_)) as u8;
+            report[2] = vial.unlock_countdown(now);
+        }
+        LOCK => vial.lock = Lock::Locked,
+        // None of QMK's settings apply here, so the list is empty
+        QMK_SETTINGS_QUERY => report.fill(0xFF),
+        DYNAMIC_ENTRY_OP => process_entry(report, keymap),
+        _ => {}
+    }
+}
+
+/// Reads or changes one of the tap dances, combos or key overrides
+fn process_entry<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    report: &mut [u8; RAW_HID_REPORT_SIZE],
+    keymap: &mut KeymapEngine<NROW, NCOL, M>,
+) {
+    let operation = report[2];
+    let index = report[3] as usize;
+    let mut entry = [0; 10];
+    entry.copy_from_slice(&report[4..14]);
+
+    let result = match operation {
+        GET_ENTRY_COUNTS => {
+            report.fill(0);
+            report[0] = MAX_TAP_DANCES as u8;
+            report[1] = MAX_COMBOS as u8;
+            report[2] = MAX_KEY_OVERRIDES as u8;
+            return;
+        }
+        GET_TAP_DANCE => keymap.tap_dance(index).map(tap_dance_entry),
+        SET_TAP_DANCE => keymap.tap_dance(index).copied().map(|current| {
+            keymap.set_tap_dance(index, tap_dance_from_entry(&current, &entry));
+            entry
+        }),
+        GET_COMBO => keymap.combo(index).map(|combo| combo_entry(keymap, combo)),
+        SET_COMBO => keymap.combo(index).copied().map(|current| {
+            let combo = combo_from_entry(keymap, &current, &entry);
+            keymap.set_combo(index, combo);
+            entry
+        }),
+        GET_KEY_OVERRIDE => keymap.key_override(index).map(key_override_entry),
+        SET_KEY_OVERRIDE => keymap.key_override(index).copied().map(|current| {
+            keymap.set_key_override(index, key_override_from_entry(&current, &entry));
+            entry
+        }),
+        _ => None,
+    };
+
+    match result {
+        Some(entry) => {
+            report[0] = ENTRY_OK;
+            report[1..11].copy_from_slice(&entry);
+        }
+        None => report[0] = ENTRY_ERROR,
+    }
+}
+
+/// The action for a keycode Vial sent back, keeping the current one if it's a keycode that can't
+/// be set, which is how actions Vial can't show come back
+fn action_or(keycode: u16, current: Action) -> Action {
+    keycodes::action(keycode).unwrap_or(current)
+}
+
+fn keycodes_to_bytes<const N: usize, const BYTES: usize>(codes: [u16; N]) -> [u8; BYTES] {
+    let mut bytes = [0; BYTES];
+    for (chunk, code) in bytes.chunks_exact_mut(2).zip(codes) {
+        chunk.copy_from_slice(&code.to_le_bytes());
+    }
+
+    bytes
+}
+
+fn keycode_at(entry: &[u8; 10], index: usize) -> u16 {
+    u16::from_le_bytes([entry[index * 2], entry[index * 2 + 1]])
+}
+
+/// A tap dance as Vial shows it: the tap, hold, double tap and tap then hold actions, then the
+/// tapping term in milliseconds. Only the first two taps can be edited.
+fn tap_dance_entry(tap_dance: &TapDance) -> [u8; 10] {
+    let taps = tap_dance.taps();
+    let holds = tap_dance.holds();
+    let tap = |n: usize| taps.get(n).copied().unwrap_or(Action::NoOp);
+
+    keycodes_to_bytes([
+        keycodes::keycode(tap(0)),
+        keycodes::keycode(holds[0]),
+        keycodes::keycode(tap(1)),
+        keycodes::keycode(holds[1]),
+        tap_dance.term.to_millis() as u16,
+    ])
+}
+
+fn tap_dance_from_entry(current: &TapDance, entry: &[u8; 10]) -> TapDance {
+    let taps = current.taps();
+    let holds = current.holds();
+    let tap = |n: usize| taps.get(n).copied().unwrap_or(Action::NoOp);
+
+    let on_tap = action_or(keycode_at(entry, 0), tap(0));
+    let on_hold = action_or(keycode_at(entry, 1), holds[0]);
+    let on_double_tap = action_or(keycode_at(entry, 2), tap(1));
+    let on_tap_hold = action_or(keycode_at(entry, 3), holds[1]);
+    let term = match keycode_at(entry, 4) {
+        0 => TAPPING_TERM,
+        millis => MicrosDurationU32::millis(millis as u32),
+    };
+
+    // Without a double tap action, tapping twice taps twice
+    let tap_dance = if on_double_tap == Action::NoOp {
+        TapDance::new(&[on_tap])
+    } else {
+        TapDance::new(&[on_tap, on_double_tap])
+    };
+    tap_dance.with_holds(&[on_hold, on_tap_hold]).term(term)
+}
+
+/// A combo as Vial shows it: up to four keys, then the action. Vial's combos are made of
+/// keycodes rather than keys, so each key is shown as what it does on the base layer.
+fn combo_entry<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    keymap: &KeymapEngine<NROW, NCOL, M>,
+    combo: &Combo,
+) -> [u8; 10] {
+    let mut codes = [0; COMBO_KEYS + 1];
+    for (code, &(row, col)) in codes.iter_mut().zip(combo.keys()) {
+        *code = base_keycode(keymap, row, col);
+    }
+    if !combo.keys().is_empty() {
+        codes[COMBO_KEYS] = keycodes::keycode(combo.action);
+    }
+
+    keycodes_to_bytes(codes)
+}
+
+/// Finds the keys with each keycode on the base layer, clearing the combo if fewer than two of
+/// them can be found
+fn combo_from_entry<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    keymap: &KeymapEngine<NROW, NCOL, M>,
+    current: &Combo,
+    entry: &[u8; 10],
+) -> Combo {
+    let mut keys = [(0, 0); COMBO_KEYS];
+    let mut key_count = 0;
+
+    for index in 0..COMBO_KEYS {
+        let code = keycode_at(entry, index);
+        if code == 0 {
+            continue;
+        }
+
+        // A key already in the combo is kept, even if another key does the same thing
+        let key = current
+            .keys()
+            .get(index)
+            .filter(|&&(row, col)| base_keycode(keymap, row, col) == code)
+            .copied()
+            .or_else(|| {
+                (0..NROW as u8)
+                    .flat_map(|row| (0..NCOL as u8).map(move |col| (row, col)))
+                    .find(|&(row, col)| base_keycode(keymap, row, col) == code)
+            });
+
+        match key {
+            Some(key) if !keys[..key_count].contains(&key) => {
+                keys[key_count] = key;
+                key_count += 1;
+            }
+            _ => return Combo::NONE,
+        }
+    }
+
+    if key_count < 2 {
+        return Combo::NONE;
+    }
+
+    let action = action_or(keycode_at(entry, COMBO_KEYS), current.action);
+    Combo::new(&keys[..key_count], action)
+}
+
+fn base_keycode<const NROW: usize, const NCOL: usize, M: KeyMap<NROW, NCOL>>(
+    keymap: &KeymapEngine<NROW, NCOL, M>,
+    row: u8,
+    col: u8,
+) -> u16 {
+    keymap
+        .keymap_action(0, row, col)
+        .map_or(0, keycodes::keycode)
+}
+
+/// A key override as Vial shows it: the trigger and replacement keycodes, the layers it is on,
+/// the modifiers that trigger it, the ones that stop it and the ones hidden while it's active,
+/// and its options. Overrides here work on every layer, with any of their modifiers.
+fn key_override_entry(key_override: &KeyOverride) -> [u8; 10] {
+    let modifiers = key_override.modifiers();
+    if modifiers.is_empty() {
+        return [0; 10];
+    }
+
+    let mut entry: [u8; 10] = keycodes_to_bytes([
+        keycodes::keycode(Action::Key(key_override.key())),
+        keycodes::keycode(key_override.replacement()),
+        u16::MAX,
+    ]);
+    entry[6] = modifiers.bits();
+    entry[7] = 0;
+    entry[8] = modifiers.bits();
+    entry[9] = OVERRIDE_ENABLED
+        | OVERRIDE_ON_TRIGGER_DOWN
+        | OVERRIDE_ON_MODIFIER_DOWN
+        | OVERRIDE_ANY_MODIFIER;
+
+    entry
+}
+
+fn key_override_from_entry(current: &KeyOverride, entry: &[u8; 10]) -> KeyOverride {
+    let modifiers = Modifiers::from_bits(entry[6]);
+    let enabled = entry[9] & OVERRIDE_ENABLED != 0;
+
+    match keycodes::action(keycode_at(entry, 0)) {
+        Some(Action::Key(key)) if enabled && !modifiers.is_empty() => KeyOverride::new(
+            modifiers,
+            key,
+            action_or(keycode_at(entry, 1), current.replacement()),
+        ),
+        // Overrides can only be triggered by plain keys
+        _ => KeyOverride::NONE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::BasicKeymap;
+    use usbd_human_interface_device::page::Keyboard;
+
+    #[test]
+    fn the_unlock_keys_vial_asks_for_are_escape_and_enter() {
+        let mut keymap = KeymapEngine::<5, 15, BasicKeymap>::new();
+        let mut vial = Vial::new();
+
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        report[1] = GET_UNLOCK_STATUS;
+        process(
+            &mut report,
+            &mut keymap,
+            &[[false; 15]; 5],
+            &mut vial,
+            Instant::from_ticks(0),
+        );
+
+        let actions: Vec<_> = report[2..6]
+            .chunks_exact(2)
+            .map(|key| keymap.keymap_action(0, key[0], key[1]))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                Some(Action::Key(Keyboard::Escape)),
+                Some(Action::Key(Keyboard::ReturnEnter)),
+            ]
+        );
+    }
+}